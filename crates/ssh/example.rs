@@ -9,25 +9,88 @@ async fn main() -> Result<()> {
     // Create or load team graph
     let (graph_id, _) = client.create_team(owner_keys, None).await?;
     
+    // Push rendered files to host agents over AFC
+    let afc_client = aranya_client::Client::connect(
+        Path::new("/var/run/aranya/uds.sock"),
+        Path::new("/afc"),
+        100,
+        "0.0.0.0:0",
+    ).await?;
+    // Agents only accept requests signed under the owner's bundle key, which
+    // they pin with `--owner-key`. Other admins sign with their own key and
+    // an endorsement chain from the owner.
+    let owner_id = afc_client.get_device_id().await?;
+    let signer = BundleSigner::load_or_generate(owner_id.into(), Path::new("/etc/aranya/ssh/bundle.key")).await?;
+    let deployer = Arc::new(AfcDeployer::new(afc_client, graph_id.into(), signer, Vec::new()));
+    deployer.add_host("server1.example.com", NetIdentifier("10.0.0.11:5050".to_string())).await;
+    deployer.add_host("server2.example.com", NetIdentifier("10.0.0.12:5050".to_string())).await;
+    
     // Initialize SSH access manager
-    let ssh_manager = SshAccessManager::new(
+    let ssh_manager = Arc::new(SshAccessManager::new(
         Arc::clone(&client),
         graph_id,
         PathBuf::from("/etc/aranya/ssh/keys"),
-        PathBuf::from("/etc/aranya/ssh/hosts")
-    );
+    ).with_deployer(deployer));
     ssh_manager.initialize().await?;
     
-    // Start background sync
-    ssh_manager.start_sync_daemon(300).await?; // Sync every 5 minutes
+    // Serve the read-only SSH API for local tools such as the sshd helper,
+    // and the admin API for aranya-ssh
+    let api_manager = Arc::clone(&ssh_manager);
+    tokio::spawn(async move {
+        serve_ssh_api(api_manager, Path::new("/var/run/aranya/ssh.sock")).await
+    });
+    let admin_manager = Arc::clone(&ssh_manager);
+    tokio::spawn(async move {
+        serve_ssh_admin_api(admin_manager, Path::new("/var/run/aranya/ssh-admin.sock")).await
+    });
+    
+    // Start background reconciliation against the team's sync peers, given
+    // as `host:port` arguments. They are kept with the manager's keys, so
+    // later runs only pass peers that are new.
+    for peer in std::env::args().skip(1) {
+        ssh_manager.add_sync_peer(peer.parse()?).await?;
+    }
+    Arc::clone(&ssh_manager).start_reconciler(300).await?; // Sync every 5 minutes
     
     // Add a user with admin SSH access
     let user_keys = KeyBundle { /* ... */ };
     let user_id = ssh_manager.add_ssh_user(user_keys, true).await?;
     
-    // Grant access to specific hosts
-    ssh_manager.grant_host_access(user_id, "server1.example.com").await?;
-    ssh_manager.grant_host_access(user_id, "server2.example.com").await?;
+    // Hosts enroll themselves from their own devices, so any device on the
+    // team can enroll any name. Approve a host only once its enrolled host
+    // key has been checked out of band, e.g. on the host's console
+    ssh_manager.approve_host("server1.example.com").await?;
+    
+    // Grant access to specific enrolled hosts
+    ssh_manager.grant_host_access(user_id, "server1.example.com", SshGrant::new(SshAccessLevel::Admin)).await?;
+    ssh_manager.grant_host_access(user_id, "server2.example.com", SshGrant::new(SshAccessLevel::Standard)).await?;
+    
+    // Grant temporary access that expires after an on-call shift
+    let shift_end = SystemTime::now() + Duration::from_secs(12 * 60 * 60);
+    ssh_manager
+        .grant_host_access(user_id, "db1.example.com", SshGrant::new(SshAccessLevel::Standard).with_expiry(shift_end))
+        .await?;
+    
+    // Grant access to every production host, including ones tagged later
+    let prod = HostTag::new("env", "prod");
+    ssh_manager.set_host_tag("server1.example.com", &prod).await?;
+    ssh_manager.set_host_tag("db1.example.com", &prod).await?;
+    ssh_manager.grant_group_access(user_id, &prod, SshGrant::new(SshAccessLevel::PortForwarding)).await?;
+    
+    // Only allow logging in as the shared deploy account on production hosts
+    ssh_manager.allow_login(user_id, &LoginPrincipal::new("deploy", Some(prod.clone()))?).await?;
     
     Ok(())
+}
+
+// On SSH hosts and workstations, the local daemon serves the SSH API from its
+// own replica of the team graph for aranya-ssh-keys. Nothing is rendered or
+// deployed from there.
+async fn serve_local_ssh_api(client: Arc<Client>, graph_id: GraphId) -> Result<()> {
+    let replica = Arc::new(SshAccessManager::new(
+        client,
+        graph_id,
+        PathBuf::from("/var/lib/aranya/ssh/keys"),
+    ));
+    serve_ssh_api(replica, Path::new("/var/run/aranya/ssh.sock")).await
 }
\ No newline at end of file
@@ -0,0 +1,505 @@
+//! SSH access levels and their OpenSSH restrictions.
+
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// How much a grant allows on a host, from least to most permissive.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum SshAccessLevel {
+    /// Runs only `command`, without a pty or any forwarding.
+    ReadOnly { command: String },
+    /// Interactive login without forwarding.
+    Standard,
+    /// Interactive login with TCP port forwarding.
+    PortForwarding,
+    /// Unrestricted login, including agent and X11 forwarding.
+    Admin,
+}
+
+impl SshAccessLevel {
+    /// The level's value in the team graph.
+    pub fn to_policy(&self) -> i64 {
+        match self {
+            Self::ReadOnly { .. } => 1,
+            Self::Standard => 2,
+            Self::PortForwarding => 3,
+            Self::Admin => 4,
+        }
+    }
+
+    /// Rebuild a level from its graph value and forced command.
+    pub fn from_policy(level: i64, command: String) -> Result<Self> {
+        Ok(match level {
+            1 => Self::ReadOnly { command },
+            2 => Self::Standard,
+            3 => Self::PortForwarding,
+            4 => Self::Admin,
+            _ => bail!("unknown SSH access level {}", level),
+        })
+    }
+
+    /// The forced command, for `ReadOnly` grants.
+    pub fn command(&self) -> Option<&str> {
+        match self {
+            Self::ReadOnly { command } => Some(command),
+            _ => None,
+        }
+    }
+}
+
+/// A device's access to one host.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SshGrant {
+    /// What the device may do on the host.
+    pub level: SshAccessLevel,
+    /// Source addresses or patterns the device may connect from. Empty
+    /// allows any source.
+    pub from: Vec<String>,
+    /// When the grant stops being honored. `None` never expires.
+    pub expires_at: Option<SystemTime>,
+}
+
+impl SshGrant {
+    /// A grant at `level` from any source.
+    pub fn new(level: SshAccessLevel) -> Self {
+        Self {
+            level,
+            from: Vec::new(),
+            expires_at: None,
+        }
+    }
+
+    /// Check that the grant can be rendered as key and certificate options.
+    ///
+    /// `ReadOnly` grants need a non-blank command. Commands and source
+    /// patterns must not contain control characters, since a newline would
+    /// end the `authorized_keys` line and let the rest start a new one, nor
+    /// backslashes, see `escape_option`. Source patterns must also be
+    /// non-empty and free of `,` and spaces.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(command) = self.level.command() {
+            if command.trim().is_empty() {
+                bail!("read-only grants need a command");
+            }
+            check_option_value("command", command)?;
+        }
+        for pattern in &self.from {
+            check_option_value("source pattern", pattern)?;
+            if pattern.is_empty() || pattern.contains([',', ' ']) {
+                bail!("invalid source pattern `{}`", pattern);
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the time the grant expires.
+    pub fn with_expiry(mut self, expires_at: SystemTime) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+
+    /// The expiry's value in the team graph, in seconds since the Unix epoch
+    /// with zero meaning no expiry.
+    pub fn expires_at_policy(&self) -> i64 {
+        self.expires_at
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| i64::try_from(d.as_secs()).unwrap_or(i64::MAX).max(1))
+    }
+
+    /// Rebuild an expiry from its graph value.
+    pub fn expiry_from_policy(expires_at: i64) -> Option<SystemTime> {
+        let secs = u64::try_from(expires_at).ok().filter(|secs| *secs > 0)?;
+        Some(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    /// Reports whether the grant has expired at `now`.
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+
+    /// Combine two grants for the same device and host, keeping the more
+    /// permissive level and, for equal levels, the later expiry.
+    pub fn broadest(self, other: Self) -> Self {
+        let lasts_longer = match (self.expires_at, other.expires_at) {
+            (_, None) => self.expires_at.is_some(),
+            (None, Some(_)) => false,
+            (Some(a), Some(b)) => b > a,
+        };
+        if other.level > self.level || (other.level == self.level && lasts_longer) {
+            other
+        } else {
+            self
+        }
+    }
+
+    /// `authorized_keys` options enforcing the grant, in the order sshd
+    /// documents them.
+    ///
+    /// Fails if the forced command or source patterns cannot be quoted.
+    pub fn authorized_keys_options(&self) -> Result<Vec<String>> {
+        let mut options = Vec::new();
+        match &self.level {
+            SshAccessLevel::ReadOnly { command } => {
+                options.push("restrict".to_string());
+                options.push(format!("command=\"{}\"", escape_option(command)?));
+            }
+            SshAccessLevel::Standard => {
+                options.push("restrict".to_string());
+                options.push("pty".to_string());
+            }
+            SshAccessLevel::PortForwarding => {
+                options.push("restrict".to_string());
+                options.push("pty".to_string());
+                options.push("port-forwarding".to_string());
+            }
+            SshAccessLevel::Admin => {}
+        }
+        if !self.from.is_empty() {
+            options.push(format!("from=\"{}\"", escape_option(&self.from.join(","))?));
+        }
+        if let Some(expires_at) = self.expires_at {
+            let expires_at = DateTime::<Utc>::from(expires_at);
+            options.push(format!(
+                "expiry-time=\"{}\"",
+                expires_at.format("%Y%m%d%H%M%SZ")
+            ));
+        }
+        Ok(options)
+    }
+
+    /// Certificate critical options enforcing the grant, sorted by name.
+    pub fn critical_options(&self) -> Vec<(String, String)> {
+        let mut options = Vec::new();
+        if let Some(command) = self.level.command() {
+            options.push(("force-command".to_string(), command.to_string()));
+        }
+        if !self.from.is_empty() {
+            options.push(("source-address".to_string(), self.from.join(",")));
+        }
+        options
+    }
+
+    /// Certificate extensions permitted by the grant, sorted by name.
+    pub fn extensions(&self) -> Vec<(String, String)> {
+        let names: &[&str] = match self.level {
+            SshAccessLevel::ReadOnly { .. } => &[],
+            SshAccessLevel::Standard => &["permit-pty"],
+            SshAccessLevel::PortForwarding => &["permit-port-forwarding", "permit-pty"],
+            SshAccessLevel::Admin => &[
+                "permit-X11-forwarding",
+                "permit-agent-forwarding",
+                "permit-port-forwarding",
+                "permit-pty",
+                "permit-user-rc",
+            ],
+        };
+        names
+            .iter()
+            .map(|name| (name.to_string(), String::new()))
+            .collect()
+    }
+}
+
+/// A `key=value` host tag, e.g. `env=prod`. Granting access to a tag covers
+/// every host carrying it.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct HostTag {
+    pub key: String,
+    pub value: String,
+}
+
+impl HostTag {
+    /// Create a tag from its key and value.
+    pub fn new(key: impl Into<String>, value: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            value: value.into(),
+        }
+    }
+}
+
+impl fmt::Display for HostTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.key, self.value)
+    }
+}
+
+impl FromStr for HostTag {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (key, value) = s
+            .split_once('=')
+            .with_context(|| format!("host tag `{}` is not `key=value`", s))?;
+        if key.is_empty() {
+            bail!("host tag `{}` has an empty key", s);
+        }
+        Ok(Self::new(key, value))
+    }
+}
+
+/// Maximum length of a unix login name.
+const MAX_LOGIN_LEN: usize = 32;
+
+/// A unix account a device may log in as.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct LoginPrincipal {
+    pub login: String,
+    /// Hosts the mapping applies on. `None` applies on every host.
+    pub group: Option<HostTag>,
+}
+
+impl LoginPrincipal {
+    /// Map to `login`, optionally only on hosts carrying `group`.
+    pub fn new(login: impl Into<String>, group: Option<HostTag>) -> Result<Self> {
+        let login = login.into();
+        validate_login(&login)?;
+        Ok(Self { login, group })
+    }
+
+    /// Reports whether the mapping applies on a host with `tags`.
+    pub fn applies_to<'a>(&self, mut tags: impl Iterator<Item = &'a HostTag>) -> bool {
+        match &self.group {
+            Some(group) => tags.any(|tag| tag == group),
+            None => true,
+        }
+    }
+}
+
+/// Check that `login` is a portable unix login name, `[a-z_][a-z0-9_-]*`.
+///
+/// Login names end up in file names on hosts, so anything else is rejected.
+pub fn validate_login(login: &str) -> Result<()> {
+    let mut chars = login.chars();
+    let valid = login.len() <= MAX_LOGIN_LEN
+        && chars
+            .next()
+            .is_some_and(|c| c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+    if !valid {
+        bail!("`{}` is not a valid login name", login);
+    }
+    Ok(())
+}
+
+/// Maximum length of a hostname, per RFC 1123.
+const MAX_HOSTNAME_LEN: usize = 253;
+
+/// Maximum length of one dot-separated hostname label.
+const MAX_HOSTNAME_LABEL_LEN: usize = 63;
+
+/// Check that `hostname` is an RFC 1123 hostname in lowercase: dot-separated
+/// labels of `[a-z0-9-]`, neither starting nor ending with `-`.
+///
+/// Hostnames end up in file names on the manager and in `known_hosts`
+/// patterns on clients, so anything else is rejected. Requiring lowercase
+/// keeps one host from being enrolled twice under different spellings.
+pub fn validate_hostname(hostname: &str) -> Result<()> {
+    let valid = !hostname.is_empty()
+        && hostname.len() <= MAX_HOSTNAME_LEN
+        && hostname.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= MAX_HOSTNAME_LABEL_LEN
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        });
+    if !valid {
+        bail!("`{}` is not a valid hostname", hostname);
+    }
+    Ok(())
+}
+
+/// Escape a value for use inside a double-quoted `authorized_keys` option.
+///
+/// sshd only unescapes `\"` and keeps every other backslash, so quotes are
+/// the only characters escaped. A trailing backslash would still turn the
+/// closing quote into `\"`, so values with backslashes are rejected, as are
+/// values with control characters.
+fn escape_option(value: &str) -> Result<String> {
+    check_option_value("option value", value)?;
+    Ok(value.replace('"', "\\\""))
+}
+
+/// Fail if `value` contains a control character or a backslash.
+fn check_option_value(what: &str, value: &str) -> Result<()> {
+    if value.chars().any(|c| c.is_control() || c == '\\') {
+        bail!("{} {:?} contains a control character or backslash", what, value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_only(command: &str) -> SshGrant {
+        SshGrant::new(SshAccessLevel::ReadOnly {
+            command: command.to_string(),
+        })
+    }
+
+    /// Unquote an option value the way sshd's `opt_dequote` does, returning
+    /// the value and the rest of the line.
+    fn dequote(s: &str) -> Option<(String, &str)> {
+        let s = s.strip_prefix('"')?;
+        let mut value = String::new();
+        let mut chars = s.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Some((value, &s[i + 1..])),
+                '\\' if chars.peek().is_some_and(|(_, next)| *next == '"') => {
+                    value.push('"');
+                    chars.next();
+                }
+                c => value.push(c),
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn options_match_each_level() {
+        let options = |level| SshGrant::new(level).authorized_keys_options().unwrap();
+        assert_eq!(options(SshAccessLevel::Standard), ["restrict", "pty"]);
+        assert_eq!(
+            options(SshAccessLevel::PortForwarding),
+            ["restrict", "pty", "port-forwarding"]
+        );
+        assert!(options(SshAccessLevel::Admin).is_empty());
+        assert_eq!(
+            read_only("uptime").authorized_keys_options().unwrap(),
+            ["restrict", "command=\"uptime\""]
+        );
+
+        let mut grant = SshGrant::new(SshAccessLevel::Admin);
+        grant.from = vec!["10.0.0.0/8".into(), "*.example.com".into()];
+        assert_eq!(
+            grant.authorized_keys_options().unwrap(),
+            ["from=\"10.0.0.0/8,*.example.com\""]
+        );
+    }
+
+    #[test]
+    fn quoted_commands_round_trip_through_sshd_dequoting() {
+        for command in ["uptime", r#"echo "hello world""#, r#"sh -c 'echo ""'"#, "\"", "\"\""] {
+            let options = read_only(command).authorized_keys_options().unwrap();
+            let quoted = options[1].strip_prefix("command=").unwrap();
+            let (value, rest) = dequote(quoted).unwrap();
+            assert_eq!(value, command);
+            assert_eq!(rest, "");
+        }
+    }
+
+    #[test]
+    fn unquotable_values_are_rejected() {
+        for command in ["ls\\", r#"ls \"x\""#, "ls\nssh-ed25519 AAAA", "ls\r"] {
+            assert!(read_only(command).validate().is_err(), "{:?}", command);
+            assert!(read_only(command).authorized_keys_options().is_err(), "{:?}", command);
+        }
+    }
+
+    #[test]
+    fn validate_rejects_blank_commands_and_bad_patterns() {
+        assert!(read_only("").validate().is_err());
+        assert!(read_only(" \t").validate().is_err());
+        assert!(read_only("uptime").validate().is_ok());
+
+        for pattern in ["", "10.0.0.1,10.0.0.2", "10.0.0.1 10.0.0.2"] {
+            let mut grant = SshGrant::new(SshAccessLevel::Standard);
+            grant.from = vec![pattern.to_string()];
+            assert!(grant.validate().is_err(), "{:?}", pattern);
+        }
+    }
+
+    #[test]
+    fn certificate_options_match_each_level() {
+        let mut grant = read_only("uptime");
+        grant.from = vec!["10.0.0.0/8".into()];
+        assert_eq!(
+            grant.critical_options(),
+            [
+                ("force-command".to_string(), "uptime".to_string()),
+                ("source-address".to_string(), "10.0.0.0/8".to_string()),
+            ]
+        );
+        assert!(grant.extensions().is_empty());
+
+        let names = |level| -> Vec<String> {
+            SshGrant::new(level).extensions().into_iter().map(|(name, _)| name).collect()
+        };
+        assert_eq!(names(SshAccessLevel::Standard), ["permit-pty"]);
+        let admin = names(SshAccessLevel::Admin);
+        assert!(admin.windows(2).all(|w| w[0] < w[1]), "{:?}", admin);
+    }
+
+    #[test]
+    fn broadest_prefers_level_then_expiry() {
+        let soon = UNIX_EPOCH + Duration::from_secs(1_000);
+        let later = UNIX_EPOCH + Duration::from_secs(2_000);
+        let standard = SshGrant::new(SshAccessLevel::Standard);
+        let admin = SshGrant::new(SshAccessLevel::Admin).with_expiry(soon);
+
+        assert_eq!(standard.clone().broadest(admin.clone()), admin);
+        assert_eq!(admin.clone().broadest(standard.clone()), admin);
+
+        let expiring = standard.clone().with_expiry(soon);
+        assert_eq!(expiring.clone().broadest(standard.clone()), standard);
+        assert_eq!(standard.clone().broadest(expiring.clone()), standard);
+        assert_eq!(
+            expiring.clone().broadest(standard.clone().with_expiry(later)).expires_at,
+            Some(later)
+        );
+        assert_eq!(read_only("uptime").broadest(standard.clone()), standard);
+    }
+
+    #[test]
+    fn host_tags_parse_key_value_pairs() {
+        assert_eq!("env=prod".parse::<HostTag>().unwrap(), HostTag::new("env", "prod"));
+        assert_eq!("role=".parse::<HostTag>().unwrap(), HostTag::new("role", ""));
+        assert_eq!("a=b=c".parse::<HostTag>().unwrap(), HostTag::new("a", "b=c"));
+        assert!("env".parse::<HostTag>().is_err());
+        assert!("=prod".parse::<HostTag>().is_err());
+        assert_eq!(HostTag::new("env", "prod").to_string(), "env=prod");
+    }
+
+    #[test]
+    fn login_names_are_portable() {
+        for login in ["deploy", "_svc", "web-01"] {
+            assert!(LoginPrincipal::new(login, None).is_ok(), "{}", login);
+        }
+        for login in ["", "Root", "1user", "../etc", "a b", &"a".repeat(MAX_LOGIN_LEN + 1)] {
+            assert!(validate_login(login).is_err(), "{}", login);
+        }
+    }
+
+    #[test]
+    fn login_mappings_apply_to_their_group() {
+        let prod = HostTag::new("env", "prod");
+        let dev = HostTag::new("env", "dev");
+        let grouped = LoginPrincipal::new("deploy", Some(prod.clone())).unwrap();
+        let everywhere = LoginPrincipal::new("deploy", None).unwrap();
+
+        assert!(grouped.applies_to([&dev, &prod].into_iter()));
+        assert!(!grouped.applies_to([&dev].into_iter()));
+        assert!(everywhere.applies_to(std::iter::empty()));
+    }
+
+    #[test]
+    fn hostnames_follow_rfc_1123_in_lowercase() {
+        for hostname in ["db1", "web-01.example.com", "10.0.0.1"] {
+            assert!(validate_hostname(hostname).is_ok(), "{}", hostname);
+        }
+        let long_label = "a".repeat(MAX_HOSTNAME_LABEL_LEN + 1);
+        for hostname in ["", "DB1", "-db1", "db1-", "db..example", "db1/../x", "db_1", &long_label] {
+            assert!(validate_hostname(hostname).is_err(), "{}", hostname);
+        }
+    }
+}
@@ -0,0 +1,370 @@
+//! UDS API exposing `SshAccessManager` to local tools.
+//!
+//! The API is served on its own Unix socket next to the daemon's UDS API.
+//! Queries only read the daemon's replica of the team graph, so every daemon
+//! running the SSH policy serves them, not just the one hosting the manager.
+//! Helpers on SSH hosts and workstations talk to their local daemon and keep
+//! working while the manager is unreachable.
+//!
+//! That socket is open to every local user, so it only answers queries.
+//! Changes are served on a separate admin socket, created with mode 0600
+//! and only accepting connections from the user serving it or root.
+
+use std::{
+    fs::Permissions,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{Context as _, Result};
+use aranya_crypto::UserId;
+use aranya_daemon_api::KeyBundle;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tarpc::{
+    client,
+    context::Context,
+    serde_transport::{self, unix},
+    server::{BaseChannel, Channel},
+    tokio_serde::formats::Json,
+};
+use tokio::{fs, net::UnixListener};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tracing::warn;
+
+use crate::{
+    ssh_access::SshGrant,
+    ssh_aranya::SshAccessManager,
+    ssh_known_hosts::KnownHosts,
+    ssh_krl::key_id_device,
+};
+
+/// Result type of the SSH API.
+pub type ApiResult<T> = core::result::Result<T, ApiError>;
+
+/// An error returned by the SSH API.
+#[derive(Clone, Debug, Serialize, Deserialize, thiserror::Error)]
+#[error("{0}")]
+pub struct ApiError(String);
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        Self(format!("{:#}", err))
+    }
+}
+
+/// A host known to the manager.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HostInfo {
+    pub hostname: String,
+    pub label: Option<u32>,
+    pub host_device: String,
+    pub approved: bool,
+    pub tags: Vec<String>,
+}
+
+/// A device's effective access to a host.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AccessEntry {
+    pub device_id: String,
+    pub hostname: String,
+    pub grant: SshGrant,
+    /// Accounts the device may log in as, `None` for any.
+    pub logins: Option<Vec<String>>,
+}
+
+#[tarpc::service]
+pub trait SshApi {
+    /// Look up the label registered for a hostname.
+    async fn host_label(hostname: String) -> ApiResult<Option<u32>>;
+    /// Look up the hostname registered for a label.
+    async fn label_host(label: u32) -> ApiResult<Option<String>>;
+    /// List the certificate principals a host accepts from the device named
+    /// by a certificate's `key_id`, logging in as `login`.
+    async fn principals(key_id: String, hostname: String, login: String) -> ApiResult<Vec<String>>;
+    /// Render the authorized_keys lines a host accepts for `login`.
+    async fn authorized_keys(hostname: String, login: String) -> ApiResult<String>;
+    /// List the unix accounts a device may log in as on a host, `None` if
+    /// the device may use any account.
+    async fn allowed_logins(device_id: String, hostname: String) -> ApiResult<Option<Vec<String>>>;
+    /// Render `known_hosts` lines for the approved hosts.
+    async fn known_hosts() -> ApiResult<KnownHosts>;
+    /// Set up SSH access management for the team. Admin socket only.
+    async fn init() -> ApiResult<()>;
+    /// Add a device to the team with an SSH role, returning its id. Admin
+    /// socket only.
+    async fn add_user(keys: KeyBundle, is_admin: bool) -> ApiResult<String>;
+    /// Remove a device's SSH access and team membership. Admin socket only.
+    async fn remove_user(device_id: String) -> ApiResult<()>;
+    /// Grant a device access to a host. Admin socket only.
+    async fn grant(device_id: String, hostname: String, grant: SshGrant) -> ApiResult<()>;
+    /// Revoke a device's access to a host. Admin socket only.
+    async fn revoke(device_id: String, hostname: String) -> ApiResult<()>;
+    /// List enrolled hosts.
+    async fn hosts() -> ApiResult<Vec<HostInfo>>;
+    /// List the devices with access to a host.
+    async fn who_can_access(hostname: String) -> ApiResult<Vec<AccessEntry>>;
+    /// List the hosts a device has access to.
+    async fn what_can(device_id: String) -> ApiResult<Vec<AccessEntry>>;
+}
+
+/// Serves the SSH API from an `SshAccessManager`.
+pub struct SshApiServer<EN, SP, CE> {
+    manager: Arc<SshAccessManager<EN, SP, CE>>,
+    /// Whether changes are served, only on the admin socket.
+    admin: bool,
+}
+
+impl<EN, SP, CE> Clone for SshApiServer<EN, SP, CE> {
+    fn clone(&self) -> Self {
+        Self {
+            manager: Arc::clone(&self.manager),
+            admin: self.admin,
+        }
+    }
+}
+
+impl<EN, SP, CE> SshApi for SshApiServer<EN, SP, CE>
+where
+    EN: Engine<Policy = VmPolicy<CE>, Effect = VmEffect> + Send + 'static,
+    SP: StorageProvider + Send + 'static,
+    CE: aranya_crypto::Engine + Send + Sync + 'static,
+{
+    async fn host_label(self, _: Context, hostname: String) -> ApiResult<Option<u32>> {
+        let label = self.manager.lookup_host_label(&hostname).await?;
+        Ok(label.map(|l| l.to_u32()))
+    }
+
+    async fn label_host(self, _: Context, label: u32) -> ApiResult<Option<String>> {
+        Ok(self.manager.lookup_label_host(Label::new(label)).await?)
+    }
+
+    async fn principals(
+        self,
+        _: Context,
+        key_id: String,
+        hostname: String,
+        login: String,
+    ) -> ApiResult<Vec<String>> {
+        let user_id = parse_device(key_id_device(&key_id))?;
+        Ok(self.manager.host_principals(user_id, &hostname, &login).await?)
+    }
+
+    async fn authorized_keys(self, _: Context, hostname: String, login: String) -> ApiResult<String> {
+        Ok(self.manager.host_keys(&hostname, &login).await?)
+    }
+
+    async fn allowed_logins(
+        self,
+        _: Context,
+        device_id: String,
+        hostname: String,
+    ) -> ApiResult<Option<Vec<String>>> {
+        let user_id = parse_device(&device_id)?;
+        let logins = self.manager.allowed_logins(user_id, &hostname).await?;
+        Ok(logins.map(|logins| logins.into_iter().collect()))
+    }
+
+    async fn known_hosts(self, _: Context) -> ApiResult<KnownHosts> {
+        Ok(self.manager.known_hosts().await?)
+    }
+
+    async fn init(self, _: Context) -> ApiResult<()> {
+        self.require_admin()?;
+        Ok(self.manager.initialize().await?)
+    }
+
+    async fn add_user(self, _: Context, keys: KeyBundle, is_admin: bool) -> ApiResult<String> {
+        self.require_admin()?;
+        let keys = aranya_daemon::policy::KeyBundle {
+            ident_key: keys.identity,
+            sign_key: keys.signing,
+            enc_key: keys.encoding,
+        };
+        let user_id = self.manager.add_ssh_user(keys, is_admin).await?;
+        Ok(user_id.to_string())
+    }
+
+    async fn remove_user(self, _: Context, device_id: String) -> ApiResult<()> {
+        self.require_admin()?;
+        Ok(self.manager.remove_ssh_user(parse_device(&device_id)?).await?)
+    }
+
+    async fn grant(
+        self,
+        _: Context,
+        device_id: String,
+        hostname: String,
+        grant: SshGrant,
+    ) -> ApiResult<()> {
+        self.require_admin()?;
+        let user_id = parse_device(&device_id)?;
+        Ok(self.manager.grant_host_access(user_id, &hostname, grant).await?)
+    }
+
+    async fn revoke(self, _: Context, device_id: String, hostname: String) -> ApiResult<()> {
+        self.require_admin()?;
+        let user_id = parse_device(&device_id)?;
+        Ok(self.manager.revoke_host_access(user_id, &hostname).await?)
+    }
+
+    async fn hosts(self, _: Context) -> ApiResult<Vec<HostInfo>> {
+        let mut hosts = Vec::new();
+        for host in self.manager.host_enrollments().await? {
+            let label = self.manager.lookup_host_label(&host.hostname).await?;
+            let tags = self.manager.host_tags(&host.hostname).await?;
+            hosts.push(HostInfo {
+                label: label.map(|l| l.to_u32()),
+                host_device: host.host_device.to_string(),
+                approved: host.approved,
+                tags: tags.iter().map(ToString::to_string).collect(),
+                hostname: host.hostname,
+            });
+        }
+        Ok(hosts)
+    }
+
+    async fn who_can_access(self, _: Context, hostname: String) -> ApiResult<Vec<AccessEntry>> {
+        let access = self.manager.host_access(&hostname).await?;
+        self.access_entries(access.into_iter().map(|(user_id, grant)| (user_id, hostname.clone(), grant)))
+            .await
+    }
+
+    async fn what_can(self, _: Context, device_id: String) -> ApiResult<Vec<AccessEntry>> {
+        let user_id = parse_device(&device_id)?;
+        let access = self.manager.device_access(user_id).await?;
+        self.access_entries(access.into_iter().map(|(hostname, grant)| (user_id, hostname, grant)))
+            .await
+    }
+}
+
+impl<EN, SP, CE> SshApiServer<EN, SP, CE>
+where
+    EN: Engine<Policy = VmPolicy<CE>, Effect = VmEffect> + Send + 'static,
+    SP: StorageProvider + Send + 'static,
+    CE: aranya_crypto::Engine + Send + Sync + 'static,
+{
+    /// Fail unless serving the admin socket.
+    fn require_admin(&self) -> ApiResult<()> {
+        if !self.admin {
+            return Err(ApiError("changes are only accepted on the admin socket".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Attach each grant's allowed logins.
+    async fn access_entries(
+        &self,
+        access: impl Iterator<Item = (UserId, String, SshGrant)>,
+    ) -> ApiResult<Vec<AccessEntry>> {
+        let mut entries = Vec::new();
+        for (user_id, hostname, grant) in access {
+            let logins = self.manager.allowed_logins(user_id, &hostname).await?;
+            entries.push(AccessEntry {
+                device_id: user_id.to_string(),
+                hostname,
+                grant,
+                logins: logins.map(|logins| logins.into_iter().collect()),
+            });
+        }
+        Ok(entries)
+    }
+}
+
+/// Parse a device id passed over the API.
+fn parse_device(device_id: &str) -> ApiResult<UserId> {
+    device_id
+        .parse()
+        .map_err(|_| ApiError(format!("`{}` is not a device id", device_id)))
+}
+
+/// Serve the read-only SSH API on the Unix socket at `path`.
+pub async fn serve_ssh_api<EN, SP, CE>(
+    manager: Arc<SshAccessManager<EN, SP, CE>>,
+    path: &Path,
+) -> Result<()>
+where
+    EN: Engine<Policy = VmPolicy<CE>, Effect = VmEffect> + Send + 'static,
+    SP: StorageProvider + Send + 'static,
+    CE: aranya_crypto::Engine + Send + Sync + 'static,
+{
+    serve(SshApiServer { manager, admin: false }, path).await
+}
+
+/// Serve the full SSH API, including changes, on the Unix socket at `path`.
+///
+/// The socket is made accessible to its owner only, and connections from
+/// any user other than the owner or root are refused.
+pub async fn serve_ssh_admin_api<EN, SP, CE>(
+    manager: Arc<SshAccessManager<EN, SP, CE>>,
+    path: &Path,
+) -> Result<()>
+where
+    EN: Engine<Policy = VmPolicy<CE>, Effect = VmEffect> + Send + 'static,
+    SP: StorageProvider + Send + 'static,
+    CE: aranya_crypto::Engine + Send + Sync + 'static,
+{
+    serve(SshApiServer { manager, admin: true }, path).await
+}
+
+/// Accept connections on `path`, checking peers of the admin socket.
+async fn serve<EN, SP, CE>(server: SshApiServer<EN, SP, CE>, path: &Path) -> Result<()>
+where
+    EN: Engine<Policy = VmPolicy<CE>, Effect = VmEffect> + Send + 'static,
+    SP: StorageProvider + Send + 'static,
+    CE: aranya_crypto::Engine + Send + Sync + 'static,
+{
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("unable to listen on {}", path.display()))?;
+    let mode = if server.admin { 0o600 } else { 0o666 };
+    fs::set_permissions(path, Permissions::from_mode(mode)).await?;
+    // The socket is created by, and so owned by, the serving user
+    let owner = fs::metadata(path).await?.uid();
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                warn!(?err, "unable to accept SSH API connection");
+                continue;
+            }
+        };
+        // The mode is set after the socket is bound, so admin connections
+        // are also checked by the peer's credentials
+        if server.admin {
+            match stream.peer_cred() {
+                Ok(cred) if cred.uid() == owner || cred.uid() == 0 => {}
+                Ok(cred) => {
+                    warn!(uid = cred.uid(), "refused SSH admin API connection");
+                    continue;
+                }
+                Err(err) => {
+                    warn!(?err, "unable to check SSH admin API peer");
+                    continue;
+                }
+            }
+        }
+        // Same framing as `unix::listen`, which hides the peer
+        let transport = serde_transport::new(
+            Framed::new(stream, LengthDelimitedCodec::new()),
+            Json::default(),
+        );
+        let server = server.clone();
+        tokio::spawn(
+            BaseChannel::with_defaults(transport)
+                .execute(server.serve())
+                .for_each(|response| async move {
+                    tokio::spawn(response);
+                }),
+        );
+    }
+}
+
+/// Connect to the SSH API served by the local daemon.
+pub async fn connect_ssh_api(path: &Path) -> Result<SshApiClient> {
+    let transport = unix::connect(path, Json::default)
+        .await
+        .with_context(|| format!("unable to connect to {}", path.display()))?;
+    Ok(SshApiClient::new(client::Config::default(), transport).spawn())
+}
@@ -1,20 +1,190 @@
-use std::{path::PathBuf, process::Command, sync::Arc};
-use anyhow::{Result, Context};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use anyhow::{bail, Result, Context};
 use tokio::{fs, time};
 use tokio::sync::Mutex;
-use aranya_crypto::UserId;
+use aranya_crypto::{Id, UserId};
 use aranya_fast_channels::Label;
+use aranya_policy_vm::Value;
+
+use crate::{
+    ssh_access::{
+        validate_hostname, validate_login, HostTag, LoginPrincipal, SshAccessLevel, SshGrant,
+    },
+    ssh_bundle::{BundleContents, BundleSigner, Endorsement, GrantBundle, MAX_BUNDLE_VALIDITY},
+    ssh_ca::{
+        CertificatePolicy, CertificateRequest, SshCertificate, SshCertificateAuthority,
+        SSH_ADMIN_PRINCIPAL,
+    },
+    ssh_deploy::{
+        write_atomic, DeployBackend, DeployStatus, HostArtifact, HostUpdate, LocalFsDeployer,
+    },
+    ssh_drift::{DriftReport, DriftSource, FileDrift, HostDrift},
+    ssh_keys::SshPublicKey,
+    ssh_known_hosts::KnownHosts,
+    ssh_krl::{certificate_key_id, device_key_ids, Revocations},
+    ssh_plan::{AccessChange, AccessPlan, HostPlan},
+    ssh_sudoers::{SudoReason, Sudoers},
+};
 
 // Define SSH-specific label and roles
 pub const SSH_LABEL: Label = Label::new(1000); // Arbitrary value
+/// Label of the channels hosts publish login audit records on
+pub const SSH_AUDIT_LABEL: Label = Label::new(1001);
 pub const SSH_ADMIN_ROLE: Role = Role::Custom(1001);
 pub const SSH_USER_ROLE: Role = Role::Custom(1002);
+pub const SSH_CA_ROLE: Role = Role::Custom(1003);
+
+/// Errors specific to SSH access management
+#[derive(Debug, thiserror::Error)]
+pub enum SshAccessError {
+    /// A command did not produce the effect needed to continue
+    #[error("command did not emit a `{0}` effect")]
+    MissingEffect(&'static str),
+    /// A failed multi-step change could not be undone
+    #[error("unable to {action} device {user_id}: {cause}; rollback also failed: {rollback}")]
+    RollbackFailed {
+        action: &'static str,
+        user_id: UserId,
+        cause: String,
+        rollback: String,
+    },
+    /// The state a plan was computed from has changed
+    #[error("plan is out of date, plan again before applying")]
+    StalePlan,
+    /// No stored authorized_keys generation matches a rollback request
+    #[error("no generation {generation} of the keys for {hostname}")]
+    UnknownGeneration { hostname: String, generation: u64 },
+}
+
+impl SshAccessError {
+    /// Report a failed change along with the failure to undo it
+    fn rollback_failed(
+        action: &'static str,
+        user_id: UserId,
+        cause: impl std::fmt::Display,
+        rollback: impl std::fmt::Display,
+    ) -> Self {
+        Self::RollbackFailed {
+            action,
+            user_id,
+            cause: format!("{:#}", cause),
+            rollback: format!("{:#}", rollback),
+        }
+    }
+}
+
+/// First label value allocated to hosts by the host registry
+pub const HOST_LABEL_BASE: u32 = 2000;
+
+/// First line of every rendered authorized_keys file
+const KEYS_HEADER: &str = "# Generated by Aranya SSH Access Manager\n";
+
+/// Number of authorized_keys generations kept per host by default
+pub const DEFAULT_KEEP_GENERATIONS: usize = 10;
 
 pub struct SshAccessManager<EN, SP, CE> {
     client: Arc<Client<EN, SP, CE>>,
     graph_id: GraphId,
     keys_path: PathBuf,
-    hosts_path: PathBuf,
+    sync_peers: Mutex<Vec<Addr>>,
+    rendered: Mutex<BTreeMap<UserId, BTreeSet<String>>>,
+    /// Hosts whose last update failed, retried on the next pass
+    pending_hosts: Mutex<BTreeSet<String>>,
+    last_reconcile: Mutex<SystemTime>,
+    deployer: Arc<dyn DeployBackend>,
+    keep_generations: usize,
+    host_cas: Vec<(String, SshPublicKey)>,
+    deploy_status: Mutex<BTreeMap<String, BTreeMap<HostArtifact, DeployStatus>>>,
+}
+
+/// A break-glass request and its approval progress
+#[derive(Clone, Debug)]
+pub struct BreakGlassRequest {
+    pub request_id: Id,
+    pub user_id: UserId,
+    pub hostname: String,
+    pub ttl: Duration,
+    pub reason: String,
+    /// When the requester made the request, by its own clock
+    pub requested_at: SystemTime,
+    pub approvals: u32,
+    /// Set once enough admins have approved
+    pub expires_at: Option<SystemTime>,
+}
+
+/// A pending request for access to a host
+#[derive(Clone, Debug)]
+pub struct AccessRequest {
+    pub request_id: Id,
+    pub user_id: UserId,
+    pub hostname: String,
+    pub level: SshAccessLevel,
+    /// How long the grant lasts once approved
+    pub duration: Duration,
+    pub reason: String,
+}
+
+/// A host in the enrollment inventory
+#[derive(Clone, Debug)]
+pub struct HostEnrollment {
+    pub hostname: String,
+    /// The host's own team device
+    pub host_device: UserId,
+    /// The host's SSH host key
+    pub host_key: SshPublicKey,
+    /// Set once an SSH admin has approved the host
+    pub approved: bool,
+}
+
+/// A host's rendered authorized_keys files and sudoers fragment
+struct RenderedKeys {
+    /// Keys of devices without login mappings, accepted for any login
+    authorized_keys: String,
+    /// Keys accepted for each mapped login
+    user_keys: BTreeMap<String, String>,
+    /// Mapped logins that may become root
+    sudoers: Sudoers,
+    /// Devices with a key in any of the files
+    devices: BTreeSet<UserId>,
+}
+
+impl RenderedKeys {
+    /// The rendered files, by artifact
+    ///
+    /// Logins in `stale` that have no keys any more are included emptied, as
+    /// they are deployed.
+    fn files(&self, stale: &BTreeSet<String>) -> BTreeMap<HostArtifact, String> {
+        let mut files = BTreeMap::new();
+        files.insert(HostArtifact::AuthorizedKeys, self.authorized_keys.clone());
+        for login in stale {
+            files.insert(HostArtifact::UserKeys(login.clone()), KEYS_HEADER.to_string());
+        }
+        for (login, keys) in &self.user_keys {
+            files.insert(HostArtifact::UserKeys(login.clone()), keys.clone());
+        }
+        files.insert(HostArtifact::Sudoers, self.sudoers.render());
+        files
+    }
+}
+
+/// A stored generation of a host's authorized_keys
+#[derive(Clone, Debug)]
+pub struct KeysGeneration {
+    pub generation: u64,
+    pub written_at: SystemTime,
+}
+
+/// A grant that expires soon
+#[derive(Clone, Debug)]
+pub struct ExpiringGrant {
+    pub user_id: UserId,
+    pub hostname: String,
+    pub grant: SshGrant,
 }
 
 impl<EN, SP, CE> SshAccessManager<EN, SP, CE>
@@ -27,15 +197,42 @@ where
         client: Arc<Client<EN, SP, CE>>,
         graph_id: GraphId,
         keys_path: PathBuf,
-        hosts_path: PathBuf,
     ) -> Self {
+        // Until a backend is configured, files land in per-host directories
+        let deployer = Arc::new(LocalFsDeployer::new(keys_path.join("hosts")));
         Self {
             client,
             graph_id,
             keys_path,
-            hosts_path,
+            sync_peers: Mutex::new(Vec::new()),
+            rendered: Mutex::new(BTreeMap::new()),
+            pending_hosts: Mutex::new(BTreeSet::new()),
+            last_reconcile: Mutex::new(SystemTime::now()),
+            deployer,
+            keep_generations: DEFAULT_KEEP_GENERATIONS,
+            host_cas: Vec::new(),
+            deploy_status: Mutex::new(BTreeMap::new()),
         }
     }
+
+    /// Use `deployer` to deliver rendered files to hosts
+    pub fn with_deployer(mut self, deployer: Arc<dyn DeployBackend>) -> Self {
+        self.deployer = deployer;
+        self
+    }
+
+    /// Keep the last `n` generations of each host's authorized_keys
+    pub fn with_keep_generations(mut self, n: usize) -> Self {
+        self.keep_generations = n.max(1);
+        self
+    }
+
+    /// Publish a host certificate CA for hosts matching `pattern` in
+    /// `known_hosts`
+    pub fn with_host_cert_authority(mut self, pattern: impl Into<String>, key: SshPublicKey) -> Self {
+        self.host_cas.push((pattern.into(), key));
+        self
+    }
     
     /// Initialize SSH access management for a team
     pub async fn initialize(&self) -> Result<()> {
@@ -44,45 +241,68 @@ where
         for effect in effects {
             println!("Effect: {}", effect.name);
         }
+        self.client.actions(&self.graph_id).define_label(SSH_AUDIT_LABEL).await?;
         
         // Create directories if they don't exist
         fs::create_dir_all(&self.keys_path).await?;
-        fs::create_dir_all(&self.hosts_path).await?;
         
         Ok(())
     }
     
     /// Add a user with SSH access
+    ///
+    /// Fails with `SshAccessError::MissingEffect` if the team does not report
+    /// the new member. If assigning the role or label fails, the member is
+    /// removed again so no partially configured device is left on the team.
     pub async fn add_ssh_user(&self, user_keys: KeyBundle, is_admin: bool) -> Result<UserId> {
         // Add member to team
         let effects = self.client.actions(&self.graph_id).add_member(user_keys.clone()).await?;
         
         // Extract user ID from effects
         let user_id = effects.iter()
-            .find_map(|e| {
-                if e.name == "member_added" {
-                    // Extract user ID from effect data (simplified)
-                    Some(UserId::new([0u8; 32])) // Replace with actual extraction
-                } else {
-                    None
-                }
-            })
-            .context("Failed to extract user ID from effects")?;
+            .filter(|e| e.name == "MemberAdded")
+            .find_map(|e| effect_id(e, "device_id"))
+            .map(UserId::from)
+            .ok_or(SshAccessError::MissingEffect("MemberAdded"))?;
         
-        // Assign appropriate role
+        // Assign appropriate role and channel access, or roll back
         let role = if is_admin { SSH_ADMIN_ROLE } else { SSH_USER_ROLE };
-        self.client.actions(&self.graph_id).assign_role(user_id, role).await?;
-        
-        // Grant channel access for SSH
-        self.client.actions(&self.graph_id)
-            .assign_label(user_id, SSH_LABEL, ChanOp::Open)
-            .await?;
+        if let Err(e) = self.assign_ssh_access(user_id, role).await {
+            if let Err(rollback) = self.client.actions(&self.graph_id)
+                .remove_member(user_id)
+                .await
+            {
+                return Err(SshAccessError::rollback_failed("add", user_id, e, rollback).into());
+            }
+            return Err(e);
+        }
         
         // Extract public key and write to authorized_keys format
         self.update_authorized_keys().await?;
         
         Ok(user_id)
     }
+
+    /// Assign a role and the SSH label, revoking the role if the label fails
+    async fn assign_ssh_access(&self, user_id: UserId, role: Role) -> Result<()> {
+        self.client.actions(&self.graph_id).assign_role(user_id, role).await?;
+        
+        // Grant channel access for SSH
+        if let Err(e) = self.client.actions(&self.graph_id)
+            .assign_label(user_id, SSH_LABEL, ChanOp::Open)
+            .await
+        {
+            if let Err(rollback) = self.client.actions(&self.graph_id)
+                .revoke_role(user_id, role)
+                .await
+            {
+                return Err(SshAccessError::rollback_failed("add", user_id, e, rollback).into());
+            }
+            return Err(e.into());
+        }
+        
+        Ok(())
+    }
     
     /// Remove SSH access for a user
     pub async fn remove_ssh_user(&self, user_id: UserId) -> Result<()> {
@@ -105,26 +325,55 @@ where
             .remove_member(user_id)
             .await?;
         
+        // Certificates already issued would otherwise stay valid
+        let revoked = self.revoke_certificates(user_id, None).await;
+
         // Update authorized_keys files
         self.update_authorized_keys().await?;
         
-        Ok(())
+        revoked
     }
     
     /// Grant SSH access to specific host
-    pub async fn grant_host_access(&self, user_id: UserId, hostname: &str) -> Result<()> {
-        // Create a specific channel for this host
-        let host_label = Label::new(self.hash_hostname(hostname));
+    ///
+    /// The grant's level and expiry are recorded in the team graph and
+    /// enforced through `authorized_keys` options or certificate extensions.
+    /// Expired grants are no longer rendered. The host must be enrolled, but
+    /// need not be approved yet.
+    pub async fn grant_host_access(&self, user_id: UserId, hostname: &str, grant: SshGrant) -> Result<()> {
+        grant.validate()?;
+
+        let host_label = self.enrolled_host_label(hostname).await?;
+        if self.lookup_label_host(host_label).await?.as_deref() != Some(hostname) {
+            bail!("label {} is not registered to host {}", host_label.to_u32(), hostname);
+        }
         
-        // Define the label
+        // Record the access level before the label, which would otherwise
+        // let the device in at standard access until the grant lands
+        let had_grant = self.host_grants(host_label).await?.contains_key(&user_id);
         self.client.actions(&self.graph_id)
-            .define_label(host_label)
+            .grant_ssh_access(user_id, host_label, grant)
             .await?;
         
-        // Assign label to user
-        self.client.actions(&self.graph_id)
-            .assign_label(user_id, host_label, ChanOp::Open)
-            .await?;
+        // Assign label to user, removing a new grant again if that fails
+        if !self.device_labels(user_id).await?.contains(&host_label) {
+            if let Err(e) = self.client.actions(&self.graph_id)
+                .assign_label(user_id, host_label, ChanOp::Open)
+                .await
+            {
+                if !had_grant {
+                    if let Err(rollback) = self.client.actions(&self.graph_id)
+                        .revoke_ssh_access(user_id, host_label)
+                        .await
+                    {
+                        return Err(SshAccessError::rollback_failed(
+                            "grant host access to", user_id, e, rollback,
+                        ).into());
+                    }
+                }
+                return Err(e.into());
+            }
+        }
         
         // Update host's authorized_keys file
         self.update_host_keys(hostname).await?;
@@ -135,124 +384,2341 @@ where
     /// Revoke SSH access to specific host
     pub async fn revoke_host_access(&self, user_id: UserId, hostname: &str) -> Result<()> {
         // Get host-specific label
-        let host_label = Label::new(self.hash_hostname(hostname));
+        let host_label = self.lookup_host_label(hostname)
+            .await?
+            .with_context(|| format!("host {} is not registered", hostname))?;
         
         // Revoke label from user
         self.client.actions(&self.graph_id)
             .revoke_label(user_id, host_label)
             .await?;
         
+        // Remove the recorded grant, if any
+        if self.host_grants(host_label).await?.contains_key(&user_id) {
+            self.client.actions(&self.graph_id)
+                .revoke_ssh_access(user_id, host_label)
+                .await?;
+        }
+        
+        let revoked = self.revoke_certificates(user_id, Some(hostname)).await;
+
         // Update host's authorized_keys file
         self.update_host_keys(hostname).await?;
         
+        revoked
+    }
+
+    /// Set a tag on an enrolled host
+    ///
+    /// The host immediately picks up any group grants for the tag.
+    pub async fn set_host_tag(&self, hostname: &str, tag: &HostTag) -> Result<()> {
+        let host_label = self.enrolled_host_label(hostname).await?;
+        
+        self.client.actions(&self.graph_id)
+            .set_ssh_host_tag(host_label, tag.clone())
+            .await?;
+        
+        self.update_host_keys(hostname).await?;
+        
         Ok(())
     }
-    
-    /// Start background synchronization process
-    pub async fn start_sync_daemon(&self, interval_secs: u64) -> Result<()> {
-        let client = Arc::clone(&self.client);
-        let graph_id = self.graph_id;
-        let keys_path = self.keys_path.clone();
+
+    /// Remove a tag from a registered host
+    pub async fn remove_host_tag(&self, hostname: &str, key: &str) -> Result<()> {
+        let host_label = self.lookup_host_label(hostname)
+            .await?
+            .with_context(|| format!("host {} is not registered", hostname))?;
         
-        tokio::spawn(async move {
-            let mut interval = time::interval(time::Duration::from_secs(interval_secs));
-            loop {
-                interval.tick().await;
-                
-                // Perform sync with peers
-                if let Err(e) = Self::sync_and_update_keys(&client, &graph_id, &keys_path).await {
-                    eprintln!("Sync error: {:?}", e);
-                }
-            }
-        });
+        self.client.actions(&self.graph_id)
+            .remove_ssh_host_tag(host_label, key.to_string())
+            .await?;
+        
+        self.update_host_keys(hostname).await?;
         
         Ok(())
     }
-    
-    /// Sync with peers and update SSH keys
-    async fn sync_and_update_keys(
-        client: &Arc<Client<EN, SP, CE>>, 
-        graph_id: &GraphId,
-        keys_path: &PathBuf
-    ) -> Result<()> {
-        // Simplified - would need actual peers and sink implementation
-        let mut sink = VecSink::new();
-        let addr = Addr::new("example.com", 8080)?;
-        
-        client.sync_peer(*graph_id, &mut sink, &addr).await?;
-        
-        // Process any effects from sync
-        let effects = sink.collect()?;
-        if !effects.is_empty() {
-            // Update authorized_keys if there were changes
-            // This would be a more complex implementation
-            println!("Received {} effects, updating keys", effects.len());
+
+    /// Get the tags of a host
+    pub async fn host_tags(&self, hostname: &str) -> Result<BTreeSet<HostTag>> {
+        let Some(host_label) = self.lookup_host_label(hostname).await? else {
+            return Ok(BTreeSet::new());
+        };
+        
+        Ok(self.all_host_tags().await?.remove(&host_label).unwrap_or_default())
+    }
+
+    /// List the hosts currently carrying a tag
+    pub async fn hosts_in_group(&self, tag: &HostTag) -> Result<Vec<String>> {
+        let hosts = self.registered_hosts().await?;
+        
+        Ok(self.all_host_tags()
+            .await?
+            .into_iter()
+            .filter(|(_, tags)| tags.contains(tag))
+            .filter_map(|(label, _)| hosts.get(&label).cloned())
+            .collect())
+    }
+
+    /// Grant SSH access to every host carrying a tag
+    ///
+    /// Hosts tagged later are covered by the same grant.
+    pub async fn grant_group_access(&self, user_id: UserId, tag: &HostTag, grant: SshGrant) -> Result<()> {
+        grant.validate()?;
+
+        self.client.actions(&self.graph_id)
+            .grant_ssh_group_access(user_id, tag.clone(), grant)
+            .await?;
+        
+        for host in self.hosts_in_group(tag).await? {
+            self.update_host_keys(&host).await?;
         }
         
         Ok(())
     }
-    
-    /// Update authorized_keys files for all hosts
-    async fn update_authorized_keys(&self) -> Result<()> {
-        // Read host list
-        let hosts = fs::read_to_string(&self.hosts_path.join("hosts.txt")).await?;
+
+    /// Revoke a group grant
+    pub async fn revoke_group_access(&self, user_id: UserId, tag: &HostTag) -> Result<()> {
+        self.client.actions(&self.graph_id)
+            .revoke_ssh_group_access(user_id, tag.clone())
+            .await?;
         
-        for host in hosts.lines() {
-            if !host.trim().is_empty() {
-                self.update_host_keys(host).await?;
-            }
+        for host in self.hosts_in_group(tag).await? {
+            self.update_host_keys(&host).await?;
         }
         
         Ok(())
     }
+
+    /// Query every host's tags, keyed by host label
+    async fn all_host_tags(&self) -> Result<BTreeMap<Label, BTreeSet<HostTag>>> {
+        let (_, effects) = self.client.actions(&self.graph_id)
+            .query_ssh_host_tags_off_graph()
+            .await?;
+
+        let mut tags: BTreeMap<Label, BTreeSet<HostTag>> = BTreeMap::new();
+        for effect in &effects {
+            let (Some(host_label), Some(key), Some(value)) = (
+                effect_label(effect, "host_label"),
+                effect_string(effect, "key"),
+                effect_string(effect, "value"),
+            ) else {
+                continue;
+            };
+            tags.entry(host_label).or_default().insert(HostTag::new(key, value));
+        }
+
+        Ok(tags)
+    }
+
+    /// Query group grants and expand them to every host carrying their tag
+    async fn expanded_group_grants(&self) -> Result<Vec<(UserId, Label, SshGrant)>> {
+        let (_, effects) = self.client.actions(&self.graph_id)
+            .query_ssh_group_grants_off_graph()
+            .await?;
+        if effects.is_empty() {
+            return Ok(Vec::new());
+        }
+        let host_tags = self.all_host_tags().await?;
+
+        let mut expanded = Vec::new();
+        for effect in &effects {
+            let (Some(user_id), Some(key), Some(value), Some(grant)) = (
+                effect_id(effect, "device_id"),
+                effect_string(effect, "key"),
+                effect_string(effect, "value"),
+                effect_ssh_grant(effect),
+            ) else {
+                continue;
+            };
+            let tag = HostTag::new(key, value);
+            for (host_label, tags) in &host_tags {
+                if tags.contains(&tag) {
+                    expanded.push((UserId::from(user_id), *host_label, grant.clone()));
+                }
+            }
+        }
+
+        Ok(expanded)
+    }
+
+    /// Resolve the unexpired grants on a host, keyed by device
+    ///
+    /// Combines label holders, direct grants, group grants and break-glass
+    /// grants. Label holders without a direct grant get standard access.
+    /// `pending` direct grants, or revokes for `None`, are applied on top of
+    /// the team graph without being recorded.
+    async fn effective_host_grants(
+        &self,
+        host_label: Option<Label>,
+        pending: &[(UserId, Option<SshGrant>)],
+    ) -> Result<BTreeMap<UserId, SshGrant>> {
+        let now = SystemTime::now();
+        let (mut holders, mut direct) = match host_label {
+            Some(label) => (self.devices_with_label(label).await?, self.host_grants(label).await?),
+            None => (BTreeSet::new(), BTreeMap::new()),
+        };
+        for (user_id, grant) in pending {
+            match grant {
+                Some(grant) => {
+                    holders.insert(*user_id);
+                    direct.insert(*user_id, grant.clone());
+                }
+                None => {
+                    holders.remove(user_id);
+                    direct.remove(user_id);
+                }
+            }
+        }
+
+        let mut grants = BTreeMap::new();
+        for user_id in holders {
+            let grant = direct
+                .get(&user_id)
+                .cloned()
+                .unwrap_or_else(|| SshGrant::new(SshAccessLevel::Standard));
+            merge_grant(&mut grants, user_id, grant, now);
+        }
+
+        // Unregistered hosts carry no tags and have no break-glass grants
+        let Some(host_label) = host_label else {
+            return Ok(grants);
+        };
+        let group = self.expanded_group_grants().await?;
+        let break_glass = self.active_break_glass().await?;
+        for (user_id, label, grant) in group.into_iter().chain(break_glass) {
+            if label == host_label {
+                merge_grant(&mut grants, user_id, grant, now);
+            }
+        }
+
+        Ok(grants)
+    }
+
+    /// Get the effective access of every device on a host
+    pub async fn host_access(&self, hostname: &str) -> Result<BTreeMap<UserId, SshGrant>> {
+        let host_label = self.lookup_host_label(hostname).await?;
+        self.effective_host_grants(host_label, &[]).await
+    }
+
+    /// Get a device's effective access, keyed by hostname
+    pub async fn device_access(&self, user_id: UserId) -> Result<BTreeMap<String, SshGrant>> {
+        let hosts = self.registered_hosts().await?;
+        Ok(self.effective_device_grants(user_id)
+            .await?
+            .into_iter()
+            .filter_map(|(label, grant)| Some((hosts.get(&label)?.clone(), grant)))
+            .collect())
+    }
+
+    /// Resolve the unexpired grants held by a device, keyed by host label
+    async fn effective_device_grants(&self, user_id: UserId) -> Result<BTreeMap<Label, SshGrant>> {
+        let now = SystemTime::now();
+        let hosts = self.registered_hosts().await?;
+        let direct = self.device_grants(user_id).await?;
+
+        let mut grants = BTreeMap::new();
+        for label in self.device_labels(user_id).await? {
+            if !hosts.contains_key(&label) {
+                continue;
+            }
+            let grant = direct
+                .get(&label)
+                .cloned()
+                .unwrap_or_else(|| SshGrant::new(SshAccessLevel::Standard));
+            merge_grant(&mut grants, label, grant, now);
+        }
+
+        let group = self.expanded_group_grants().await?;
+        let break_glass = self.active_break_glass().await?;
+        for (grantee, label, grant) in group.into_iter().chain(break_glass) {
+            if grantee == user_id {
+                merge_grant(&mut grants, label, grant, now);
+            }
+        }
+
+        Ok(grants)
+    }
+
+    /// Query the grants recorded for a host, keyed by device
+    async fn host_grants(&self, host_label: Label) -> Result<BTreeMap<UserId, SshGrant>> {
+        let (_, effects) = self.client.actions(&self.graph_id)
+            .query_ssh_grants_for_host_off_graph(host_label)
+            .await?;
+
+        Ok(effects
+            .iter()
+            .filter_map(effect_grant)
+            .map(|(user_id, _, grant)| (user_id, grant))
+            .collect())
+    }
+
+    /// Query the grants held by a device, keyed by host label
+    async fn device_grants(&self, user_id: UserId) -> Result<BTreeMap<Label, SshGrant>> {
+        let (_, effects) = self.client.actions(&self.graph_id)
+            .query_ssh_grants_for_device_off_graph(user_id)
+            .await?;
+
+        Ok(effects
+            .iter()
+            .filter_map(effect_grant)
+            .map(|(_, host_label, grant)| (host_label, grant))
+            .collect())
+    }
     
-    /// Update authorized_keys for a specific host
-    async fn update_host_keys(&self, hostname: &str) -> Result<()> {
-        // In a real implementation, this would:
-        // 1. Query Aranya for users with access to this host
-        // 2. Extract their public keys
-        // 3. Format them as SSH authorized_keys
-        // 4. Distribute to the host (via SSH, configuration management, etc.)
+    /// List grants that expire within `within` from now
+    ///
+    /// Includes group grants, once per host they cover, and active
+    /// break-glass grants. Grants that have already expired are not included.
+    pub async fn expiring_grants(&self, within: Duration) -> Result<Vec<ExpiringGrant>> {
+        let now = SystemTime::now();
+        self.grants_expiring_between(now, now + within).await
+    }
+
+    /// Query grants whose expiry falls in `(after, until]`
+    async fn grants_expiring_between(
+        &self,
+        after: SystemTime,
+        until: SystemTime,
+    ) -> Result<Vec<ExpiringGrant>> {
+        let (_, effects) = self.client.actions(&self.graph_id)
+            .query_ssh_grants_off_graph()
+            .await?;
+        let hosts = self.registered_hosts().await?;
+
+        let group = self.expanded_group_grants().await?;
+        let break_glass = self.active_break_glass().await?;
+
+        let mut expiring = Vec::new();
+        let grants = effects
+            .iter()
+            .filter_map(effect_grant)
+            .chain(group)
+            .chain(break_glass);
+        for (user_id, host_label, grant) in grants {
+            let Some(expires_at) = grant.expires_at else {
+                continue;
+            };
+            if expires_at <= after || expires_at > until {
+                continue;
+            }
+            if let Some(hostname) = hosts.get(&host_label) {
+                expiring.push(ExpiringGrant {
+                    user_id,
+                    hostname: hostname.clone(),
+                    grant,
+                });
+            }
+        }
+        expiring.sort_by_key(|e| e.grant.expires_at);
+
+        Ok(expiring)
+    }
+
+    /// Set how many SSH admins must approve a break-glass request
+    ///
+    /// The policy accepts between one and five approvals.
+    pub async fn set_break_glass_threshold(&self, approvals: u32) -> Result<()> {
+        self.client.actions(&self.graph_id)
+            .set_ssh_break_glass_threshold(approvals)
+            .await?;
         
-        // Simplified example:
-        let authorized_keys = format!("# Generated by Aranya SSH Access Manager\n");
-        let keys_file = self.keys_path.join(format!("{}.keys", hostname));
-        fs::write(&keys_file, authorized_keys).await?;
+        Ok(())
+    }
+
+    /// Request emergency access to a host for `ttl`
+    ///
+    /// Returns the request id that approvers pass to `approve_break_glass`.
+    pub async fn request_break_glass(&self, hostname: &str, ttl: Duration, reason: &str) -> Result<Id> {
+        let host_label = self.lookup_host_label(hostname)
+            .await?
+            .with_context(|| format!("host {} is not registered", hostname))?;
+
+        let effects = self.client.actions(&self.graph_id)
+            .request_ssh_break_glass(host_label, ttl, reason.to_string(), SystemTime::now())
+            .await?;
+
+        let request_id = effects.iter()
+            .filter(|e| e.name == "SshBreakGlassRequested")
+            .find_map(|e| effect_id(e, "request_id"))
+            .ok_or(SshAccessError::MissingEffect("SshBreakGlassRequested"))?;
         
-        // Distribute keys to host
-        self.deploy_keys_to_host(hostname, &keys_file).await?;
+        Ok(request_id)
+    }
+
+    /// Approve a break-glass request
+    ///
+    /// Returns true if this approval reached the threshold and activated the
+    /// grant, in which case the host's keys are updated immediately. The
+    /// policy rejects activations more than a day after the request, or once
+    /// an earlier approver no longer holds `SSH_ADMIN_ROLE`.
+    pub async fn approve_break_glass(&self, request_id: Id) -> Result<bool> {
+        let effects = self.client.actions(&self.graph_id)
+            .approve_ssh_break_glass(request_id, SystemTime::now())
+            .await?;
+
+        let Some(host_label) = effects.iter()
+            .filter(|e| e.name == "SshBreakGlassActivated")
+            .find_map(|e| effect_label(e, "host_label"))
+        else {
+            return Ok(false);
+        };
+
+        if let Some(hostname) = self.lookup_label_host(host_label).await? {
+            self.update_host_keys(&hostname).await?;
+        }
         
+        Ok(true)
+    }
+
+    /// List every break-glass request, pending or active
+    pub async fn break_glass_requests(&self) -> Result<Vec<BreakGlassRequest>> {
+        let (_, effects) = self.client.actions(&self.graph_id)
+            .query_ssh_break_glass_requests_off_graph()
+            .await?;
+        let (_, active) = self.client.actions(&self.graph_id)
+            .query_ssh_break_glass_active_off_graph()
+            .await?;
+        let hosts = self.registered_hosts().await?;
+
+        let expiries: BTreeMap<Id, SystemTime> = active
+            .iter()
+            .filter_map(|e| {
+                let expires_at = match effect_field(e, "expires_at")? {
+                    Value::Int(n) => SshGrant::expiry_from_policy(*n)?,
+                    _ => return None,
+                };
+                Some((effect_id(e, "request_id")?, expires_at))
+            })
+            .collect();
+
+        let mut requests = Vec::new();
+        for effect in &effects {
+            let (Some(request_id), Some(user_id), Some(host_label)) = (
+                effect_id(effect, "request_id"),
+                effect_id(effect, "device_id"),
+                effect_label(effect, "host_label"),
+            ) else {
+                continue;
+            };
+            let int = |key| match effect_field(effect, key) {
+                Some(Value::Int(n)) => u64::try_from(*n).unwrap_or(0),
+                _ => 0,
+            };
+            requests.push(BreakGlassRequest {
+                request_id,
+                user_id: UserId::from(user_id),
+                hostname: hosts.get(&host_label).cloned().unwrap_or_default(),
+                ttl: Duration::from_secs(int("ttl")),
+                reason: effect_string(effect, "reason").unwrap_or_default(),
+                requested_at: UNIX_EPOCH + Duration::from_secs(int("requested_at")),
+                approvals: u32::try_from(int("approvals")).unwrap_or(u32::MAX),
+                expires_at: expiries.get(&request_id).copied(),
+            });
+        }
+
+        Ok(requests)
+    }
+
+    /// Ask for access to a host for `duration`
+    ///
+    /// Returns the request id that SSH admins pass to
+    /// `approve_access_request` or `deny_access_request`.
+    pub async fn request_host_access(
+        &self,
+        hostname: &str,
+        level: SshAccessLevel,
+        reason: &str,
+        duration: Duration,
+    ) -> Result<Id> {
+        let host_label = self.lookup_host_label(hostname)
+            .await?
+            .with_context(|| format!("host {} is not registered", hostname))?;
+
+        let effects = self.client.actions(&self.graph_id)
+            .request_ssh_access(host_label, level, duration, reason.to_string())
+            .await?;
+
+        let request_id = effects.iter()
+            .filter(|e| e.name == "SshAccessRequested")
+            .find_map(|e| effect_id(e, "request_id"))
+            .ok_or(SshAccessError::MissingEffect("SshAccessRequested"))?;
+
+        Ok(request_id)
+    }
+
+    /// Approve an access request, granting the requested access
+    ///
+    /// The grant expires the requested duration from now. The requester is
+    /// given the host's label if needed and the host's keys are updated
+    /// immediately. Fails if the requester already has a grant on the host,
+    /// which must be changed with `grant_host_access` instead.
+    pub async fn approve_access_request(&self, request_id: Id) -> Result<()> {
+        let effects = self.client.actions(&self.graph_id)
+            .approve_ssh_access_request(request_id, SystemTime::now())
+            .await?;
+
+        let (user_id, host_label) = effects.iter()
+            .filter(|e| e.name == "SshAccessRequestApproved")
+            .find_map(|e| Some((UserId::from(effect_id(e, "device_id")?), effect_label(e, "host_label")?)))
+            .ok_or(SshAccessError::MissingEffect("SshAccessRequestApproved"))?;
+
+        if !self.device_labels(user_id).await?.contains(&host_label) {
+            self.client.actions(&self.graph_id)
+                .assign_label(user_id, host_label, ChanOp::Open)
+                .await?;
+        }
+        if let Some(hostname) = self.lookup_label_host(host_label).await? {
+            self.update_host_keys(&hostname).await?;
+        }
+
         Ok(())
     }
-    
-    /// Deploy keys to a host
-    async fn deploy_keys_to_host(&self, hostname: &str, keys_file: &PathBuf) -> Result<()> {
-        // In a real implementation, this would use SSH, configuration management,
-        // or another secure method to deploy the keys to the target host
-        
-        // Example using scp (would run in a real implementation):
-        /*
-        Command::new("scp")
-            .arg(keys_file.to_str().unwrap())
-            .arg(format!("admin@{}:/etc/ssh/authorized_keys", hostname))
-            .output()
-            .await
-            .context("Failed to deploy keys to host")?;
-        */
-        
-        println!("Deployed keys to host: {}", hostname);
+
+    /// Deny an access request, recording why
+    pub async fn deny_access_request(&self, request_id: Id, reason: &str) -> Result<()> {
+        let effects = self.client.actions(&self.graph_id)
+            .deny_ssh_access_request(request_id, reason.to_string())
+            .await?;
+        if !effects.iter().any(|e| e.name == "SshAccessRequestDenied") {
+            return Err(SshAccessError::MissingEffect("SshAccessRequestDenied").into());
+        }
+
         Ok(())
     }
+
+    /// List the access requests awaiting a decision
+    pub async fn pending_access_requests(&self) -> Result<Vec<AccessRequest>> {
+        let (_, effects) = self.client.actions(&self.graph_id)
+            .query_ssh_access_requests_off_graph()
+            .await?;
+        let hosts = self.registered_hosts().await?;
+
+        Ok(effects.iter().filter_map(|e| effect_access_request(e, &hosts)).collect())
+    }
+
+    /// Query active break-glass grants as admin-level grants
+    async fn active_break_glass(&self) -> Result<Vec<(UserId, Label, SshGrant)>> {
+        let (_, effects) = self.client.actions(&self.graph_id)
+            .query_ssh_break_glass_active_off_graph()
+            .await?;
+
+        Ok(effects
+            .iter()
+            .filter_map(|e| {
+                let user_id = UserId::from(effect_id(e, "device_id")?);
+                let host_label = effect_label(e, "host_label")?;
+                let expires_at = match effect_field(e, "expires_at")? {
+                    Value::Int(n) => SshGrant::expiry_from_policy(*n)?,
+                    _ => return None,
+                };
+                let grant = SshGrant::new(SshAccessLevel::Admin).with_expiry(expires_at);
+                Some((user_id, host_label, grant))
+            })
+            .collect())
+    }
+
+    /// Register a host, allocating it a unique label
+    ///
+    /// Returns the existing label if the host is already registered. The
+    /// policy defines the label and binds it to the hostname in one command,
+    /// rejecting taken hostnames and labels, so a failed registration is
+    /// retried with the next free value unless the host was registered
+    /// concurrently.
+    pub async fn register_host(&self, hostname: &str) -> Result<Label> {
+        const MAX_ATTEMPTS: usize = 3;
+        validate_hostname(hostname)?;
+
+        let mut candidate = HOST_LABEL_BASE;
+        let mut last_err = None;
+        for _ in 0..MAX_ATTEMPTS {
+            if let Some(label) = self.lookup_host_label(hostname).await? {
+                return Ok(label);
+            }
+
+            // Allocate past the highest registered or retired label and
+            // past any label a previous attempt found taken
+            let mut allocated = self.registered_hosts().await?;
+            allocated.extend(self.retired_host_labels().await?);
+            let next = next_host_label(&allocated, candidate);
+            let host_label = Label::new(next);
+
+            match self.client.actions(&self.graph_id)
+                .register_ssh_host(hostname.to_string(), host_label)
+                .await
+            {
+                Ok(effects) if effects.iter().any(|e| e.name == "SshHostRegistered") => {
+                    return Ok(host_label);
+                }
+                Ok(_) => {}
+                Err(e) => last_err = Some(e),
+            }
+            candidate = next + 1;
+        }
+
+        match last_err {
+            Some(e) => Err(e).with_context(|| format!("unable to allocate a label for host {}", hostname)),
+            None => bail!("unable to allocate a label for host {}", hostname),
+        }
+    }
+
+    /// Get the label of an enrolled host, registering one if it has none
+    ///
+    /// Fails for hosts that are not enrolled, so grants and tags are never
+    /// recorded against a name no host has claimed. A host enrolled again
+    /// after being decommissioned is registered with a fresh label.
+    async fn enrolled_host_label(&self, hostname: &str) -> Result<Label> {
+        if self.host_enrollment(hostname).await?.is_none() {
+            bail!("host {} is not enrolled", hostname);
+        }
+        self.register_host(hostname).await
+    }
+
+    /// Look up the label registered for a hostname
+    pub async fn lookup_host_label(&self, hostname: &str) -> Result<Option<Label>> {
+        let (_, effects) = self.client.actions(&self.graph_id)
+            .query_ssh_host_by_name_off_graph(hostname.to_string())
+            .await?;
+
+        Ok(effects.iter().find_map(|e| effect_label(e, "label")))
+    }
+
+    /// Look up the hostname registered for a label
+    pub async fn lookup_label_host(&self, label: Label) -> Result<Option<String>> {
+        let (_, effects) = self.client.actions(&self.graph_id)
+            .query_ssh_host_by_label_off_graph(label)
+            .await?;
+
+        Ok(effects.iter().find_map(|e| effect_hostname(e, "hostname")))
+    }
+
+    /// Query every registered host, keyed by label
+    async fn registered_hosts(&self) -> Result<BTreeMap<Label, String>> {
+        let (_, effects) = self.client.actions(&self.graph_id)
+            .query_ssh_hosts_off_graph()
+            .await?;
+
+        Ok(effects
+            .iter()
+            .filter_map(|e| Some((effect_label(e, "label")?, effect_hostname(e, "hostname")?)))
+            .collect())
+    }
+
+    /// Query the labels of decommissioned hosts, with the hostname each was
+    /// registered to
+    async fn retired_host_labels(&self) -> Result<BTreeMap<Label, String>> {
+        let (_, effects) = self.client.actions(&self.graph_id)
+            .query_ssh_retired_host_labels_off_graph()
+            .await?;
+
+        Ok(effects
+            .iter()
+            .filter_map(|e| Some((effect_label(e, "label")?, effect_string(e, "hostname")?)))
+            .collect())
+    }
     
-    /// Generate a deterministic hash for a hostname to use as label
-    fn hash_hostname(&self, hostname: &str) -> u32 {
-        // Simple hash function for demonstration
-        // In production, use a proper hashing algorithm
-        let mut hash: u32 = 0;
-        for byte in hostname.bytes() {
-            hash = hash.wrapping_mul(31).wrapping_add(byte as u32);
-        }
-        // Reserve a range for host labels
-        2000 + (hash % 1000)
-    }
-}
\ No newline at end of file
+    /// Issue a short-lived certificate for a team device
+    ///
+    /// Principals are the hosts the device may access, plus
+    /// `SSH_ADMIN_PRINCIPAL` for admins. Devices with login mappings get
+    /// `login@host` principals instead of the bare hostname. Validity comes
+    /// from the device's role and it expires no later than the earliest
+    /// grant it covers.
+    ///
+    /// A certificate is accepted on every host it names, so it carries the
+    /// restrictions of all of the device's grants: the lowest level, only
+    /// the source patterns every restricted grant lists, and the forced
+    /// command. Issuing fails if the grants force different commands or
+    /// share no source pattern. Admins are unrestricted.
+    pub async fn issue_certificate(
+        &self,
+        ca: &SshCertificateAuthority,
+        user_id: UserId,
+        policy: &CertificatePolicy,
+    ) -> Result<SshCertificate> {
+        // Only the local device may sign, and only while it holds the CA role
+        let device_id = self.client.get_device_id().await?;
+        if ca.device_id() != device_id {
+            bail!("CA key belongs to {}, not the local device {}", ca.device_id(), device_id);
+        }
+        if !self.device_roles(device_id).await?.contains(&SSH_CA_ROLE) {
+            bail!("device {} does not hold the SSH CA role", device_id);
+        }
+
+        let roles = self.device_roles(user_id).await?;
+        let is_admin = roles.contains(&SSH_ADMIN_ROLE);
+        let mut principals = Vec::new();
+        let validity = if is_admin {
+            principals.push(SSH_ADMIN_PRINCIPAL.to_string());
+            policy.admin_validity
+        } else if roles.contains(&SSH_USER_ROLE) {
+            policy.user_validity
+        } else {
+            bail!("device {} holds no SSH role", user_id);
+        };
+
+        let hosts = self.registered_hosts().await?;
+        let logins = self.login_principals().await?;
+        let host_tags = self.all_host_tags().await?;
+        let mut level = SshAccessLevel::Admin;
+        let mut command: Option<String> = None;
+        let mut from: Option<BTreeSet<String>> = None;
+        let mut valid_before = SystemTime::now() + validity;
+        for (label, grant) in self.effective_device_grants(user_id).await? {
+            let Some(host) = hosts.get(&label) else {
+                continue;
+            };
+            let tags = host_tags.get(&label).cloned().unwrap_or_default();
+            match host_logins(logins.get(&user_id), &tags) {
+                None => principals.push(host.clone()),
+                Some(allowed) => {
+                    principals.extend(allowed.iter().map(|login| format!("{}@{}", login, host)));
+                }
+            }
+            // The certificate must not outlive any grant it covers
+            if let Some(expires_at) = grant.expires_at {
+                valid_before = valid_before.min(expires_at);
+            }
+            if is_admin {
+                continue;
+            }
+
+            if grant.level.to_policy() < level.to_policy() {
+                level = grant.level.clone();
+            }
+            if let Some(forced) = grant.level.command() {
+                match &command {
+                    Some(existing) if existing != forced => bail!(
+                        "grants of device {} force different commands, so no single certificate can cover them",
+                        user_id
+                    ),
+                    _ => command = Some(forced.to_string()),
+                }
+            }
+            if !grant.from.is_empty() {
+                let patterns: BTreeSet<String> = grant.from.iter().cloned().collect();
+                from = Some(match from {
+                    Some(allowed) => allowed.intersection(&patterns).cloned().collect(),
+                    None => patterns,
+                });
+            }
+        }
+        if from.as_ref().is_some_and(BTreeSet::is_empty) {
+            bail!("grants of device {} share no source pattern", user_id);
+        }
+
+        let mut restriction = SshGrant::new(match command {
+            Some(command) => SshAccessLevel::ReadOnly { command },
+            None => level,
+        });
+        restriction.from = from.into_iter().flatten().collect();
+
+        let key = self.device_ssh_key(user_id).await?;
+        // Certificates of earlier generations may be revoked on some hosts
+        let device_id = user_id.to_string();
+        let generation = self.key_generations().await?.get(&device_id).copied().unwrap_or(0);
+        let key_id = certificate_key_id(&device_id, generation);
+        let mut request = CertificateRequest::new(key_id, principals, validity);
+        request.valid_before = valid_before;
+        request.critical_options = restriction.critical_options();
+        request.extensions = restriction.extensions();
+        ca.sign(&key, &request)
+    }
+
+    /// List the certificate principals a host accepts from a device
+    ///
+    /// Served to `AuthorizedPrincipalsCommand` on hosts, so it follows the
+    /// same grants as `issue_certificate`. Devices removed from the team get
+    /// none, even while their certificates are still valid. Devices with
+    /// login mappings are only accepted as `login@host` for a mapped `login`.
+    pub async fn host_principals(&self, user_id: UserId, hostname: &str, login: &str) -> Result<Vec<String>> {
+        let mut principals = Vec::new();
+        if !self.team_devices().await?.contains(&user_id) {
+            return Ok(principals);
+        }
+
+        // Direct, group and break-glass grants, as the CA resolves them
+        if let Some(label) = self.lookup_host_label(hostname).await? {
+            if self.effective_host_grants(Some(label), &[]).await?.contains_key(&user_id) {
+                match self.allowed_logins(user_id, hostname).await? {
+                    None => principals.push(hostname.to_string()),
+                    Some(logins) if logins.contains(login) => {
+                        principals.push(format!("{}@{}", login, hostname));
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        if self.device_roles(user_id).await?.contains(&SSH_ADMIN_ROLE) {
+            principals.push(SSH_ADMIN_PRINCIPAL.to_string());
+        }
+
+        Ok(principals)
+    }
+
+    /// Configure a host to trust the CA instead of per-user keys
+    ///
+    /// Writes the key for `TrustedUserCAKeys` and the host's
+    /// `AuthorizedPrincipalsFile`, then deploys both. The file only accepts
+    /// devices without login mappings; mapped devices need the
+    /// `aranya-ssh-keys principals` helper.
+    pub async fn write_ca_trust(&self, ca: &SshCertificateAuthority, hostname: &str) -> Result<()> {
+        let ca_trust = format!("{}\n", ca.public_key());
+        let ca_file = self.keys_path.join(format!("{}.ca.pub", hostname));
+        write_atomic(&ca_file, ca_trust.as_bytes()).await?;
+        self.deploy_to_host(hostname, HostArtifact::CaTrust, ca_trust.into_bytes()).await?;
+
+        let principals = format!("{}\n{}\n", hostname, SSH_ADMIN_PRINCIPAL);
+        let principals_file = self.keys_path.join(format!("{}.principals", hostname));
+        write_atomic(&principals_file, principals.as_bytes()).await?;
+        self.deploy_to_host(hostname, HostArtifact::Principals, principals.into_bytes()).await?;
+
+        Ok(())
+    }
+
+    /// Export a signed bundle of a host's grants for offline enforcement
+    ///
+    /// `chain` must lead from a device holding the owner role through
+    /// devices holding `SSH_ADMIN_ROLE` to `signer`, or be empty if `signer`
+    /// is the owner. The bundle expires after `validity`, capped at
+    /// `MAX_BUNDLE_VALIDITY`, or earlier if an endorsement does.
+    pub async fn export_grant_bundle(
+        &self,
+        hostname: &str,
+        signer: &BundleSigner,
+        chain: Vec<Endorsement>,
+        validity: Duration,
+    ) -> Result<GrantBundle> {
+        if !self.host_enrollment(hostname).await?.is_some_and(|e| e.approved) {
+            bail!("host {} is not approved", hostname);
+        }
+
+        // The owner must root the chain, and every link after it be an admin
+        let root = chain.first().map_or(signer.device_id().to_string(), |l| l.endorser.clone());
+        if !self.device_roles(parse_user_id(&root)?).await?.contains(&Role::Owner) {
+            bail!("signer chain starts at {}, which is not the team owner", root);
+        }
+        let mut endorser = root;
+        for link in &chain {
+            if link.endorser != endorser {
+                bail!("endorsement of {} is not by {}", link.device_id, endorser);
+            }
+            if !self.device_roles(parse_user_id(&link.device_id)?).await?.contains(&SSH_ADMIN_ROLE) {
+                bail!("device {} does not hold the SSH admin role", link.device_id);
+            }
+            endorser = link.device_id.clone();
+        }
+        if endorser != signer.device_id().to_string()
+            || chain.last().is_some_and(|l| l.key != *signer.public_key().ed25519())
+        {
+            bail!("signer chain does not end at {}", signer.device_id());
+        }
+
+        let now = SystemTime::now();
+        let issued_at = now.duration_since(UNIX_EPOCH)?.as_secs();
+        let expires_at = chain
+            .iter()
+            .map(|l| l.expires_at)
+            .fold(issued_at + validity.min(MAX_BUNDLE_VALIDITY).as_secs(), u64::min);
+        let host_label = self.lookup_host_label(hostname).await?;
+        let grants = self.effective_host_grants(host_label, &[]).await?;
+        let rendered = self.render_host_keys(hostname, &[]).await?;
+
+        signer.sign(
+            BundleContents {
+                hostname: hostname.to_string(),
+                issued_at,
+                expires_at,
+                grants: grants
+                    .into_iter()
+                    .map(|(user_id, grant)| (user_id.to_string(), grant))
+                    .collect(),
+                authorized_keys: rendered.authorized_keys,
+                user_keys: rendered.user_keys,
+            },
+            chain,
+        )
+    }
+    
+    /// Add a peer the reconciler syncs the team with
+    ///
+    /// Peers are kept in the keys directory, so they only need adding once.
+    pub async fn add_sync_peer(&self, addr: Addr) -> Result<()> {
+        let mut peers = self.sync_peers.lock().await;
+        if !peers.contains(&addr) {
+            peers.push(addr);
+            self.store_sync_peers(&peers).await?;
+        }
+        Ok(())
+    }
+
+    /// Stop syncing the team with a peer
+    pub async fn remove_sync_peer(&self, addr: &Addr) -> Result<()> {
+        let mut peers = self.sync_peers.lock().await;
+        peers.retain(|peer| peer != addr);
+        self.store_sync_peers(&peers).await
+    }
+
+    /// Path of the team's sync peers, one `host:port` per line
+    fn sync_peers_file(&self) -> PathBuf {
+        self.keys_path.join("sync_peers")
+    }
+
+    async fn store_sync_peers(&self, peers: &[Addr]) -> Result<()> {
+        let contents: String = peers.iter().map(|addr| format!("{}\n", addr)).collect();
+        fs::write(self.sync_peers_file(), contents).await?;
+        Ok(())
+    }
+
+    /// Load the stored sync peers, keeping any added since
+    async fn load_sync_peers(&self) -> Result<()> {
+        let path = self.sync_peers_file();
+        let contents = match fs::read_to_string(&path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut peers = self.sync_peers.lock().await;
+        for line in contents.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let addr: Addr = line
+                .parse()
+                .with_context(|| format!("invalid sync peer `{}` in {}", line, path.display()))?;
+            if !peers.contains(&addr) {
+                peers.push(addr);
+            }
+        }
+        Ok(())
+    }
+
+    /// Start background reconciliation
+    ///
+    /// Renders every host once, then syncs with the team's peers every
+    /// `interval_secs` and re-renders only the hosts affected by the
+    /// effects received or by grants that expired since the last pass. Hosts
+    /// that fail to update are retried on later passes.
+    pub async fn start_reconciler(self: Arc<Self>, interval_secs: u64) -> Result<()> {
+        self.load_sync_peers().await?;
+        if self.sync_peers.lock().await.is_empty() {
+            eprintln!("No sync peers configured, only local changes will be reconciled");
+        }
+        self.update_authorized_keys().await?;
+        
+        tokio::spawn(async move {
+            let mut interval = time::interval(time::Duration::from_secs(interval_secs));
+            loop {
+                interval.tick().await;
+                
+                if let Err(e) = self.reconcile().await {
+                    eprintln!("Reconcile error: {:?}", e);
+                }
+            }
+        });
+        
+        Ok(())
+    }
+    
+    /// Sync with every peer and update the hosts affected by new effects
+    ///
+    /// Hosts that fail to update are queued and retried on the next pass
+    /// along with the newly affected ones.
+    async fn reconcile(&self) -> Result<()> {
+        let peers = self.sync_peers.lock().await.clone();
+
+        let mut effects = Vec::new();
+        for addr in &peers {
+            let mut sink = VecSink::new();
+            // A single unreachable peer must not stall the others
+            if let Err(e) = self.client.sync_peer(self.graph_id, &mut sink, addr).await {
+                eprintln!("Sync error with {}: {:?}", addr, e);
+                continue;
+            }
+            effects.extend(sink.collect()?);
+        }
+
+        self.revoke_from_effects(&effects).await?;
+        let mut affected = self.affected_hosts(&effects).await?;
+
+        // Grants that expired since the last successful pass must be removed
+        // too
+        let now = SystemTime::now();
+        let last = *self.last_reconcile.lock().await;
+        for expired in self.grants_expiring_between(last, now).await? {
+            affected.insert(expired.hostname);
+        }
+        affected.append(&mut *self.pending_hosts.lock().await);
+
+        let mut failed = BTreeSet::new();
+        for host in affected {
+            if let Err(e) = self.update_host_keys(&host).await {
+                eprintln!("Unable to update {}: {:?}", host, e);
+                failed.insert(host);
+            }
+        }
+        // Only move past expiries once every host they affected is updated
+        if failed.is_empty() {
+            *self.last_reconcile.lock().await = now;
+        } else {
+            let count = failed.len();
+            self.pending_hosts.lock().await.extend(failed);
+            bail!("{} hosts failed to update and will be retried", count);
+        }
+        
+        Ok(())
+    }
+
+    /// Map effects to the hosts whose key material they change
+    async fn affected_hosts(&self, effects: &[VmEffect]) -> Result<BTreeSet<String>> {
+        let mut affected = BTreeSet::new();
+        if effects.is_empty() {
+            return Ok(affected);
+        }
+
+        let hosts = self.registered_hosts().await?;
+        let host_tags = self.all_host_tags().await?;
+        let rendered = self.rendered.lock().await;
+        for effect in effects {
+            match effect.name.as_str() {
+                // Label changes only touch the host owning the label
+                "LabelAssigned" | "LabelRevoked" => {
+                    let host = effect_label(effect, "label").and_then(|l| hosts.get(&l));
+                    affected.extend(host.cloned());
+                }
+                "SshAccessGranted" | "SshAccessRevoked" | "SshBreakGlassActivated" => {
+                    let host = effect_label(effect, "host_label").and_then(|l| hosts.get(&l));
+                    affected.extend(host.cloned());
+                }
+                "SshHostTagSet" | "SshHostTagRemoved" => {
+                    let host = effect_label(effect, "host_label").and_then(|l| hosts.get(&l));
+                    affected.extend(host.cloned());
+                }
+                // Group grants touch every host currently carrying the tag
+                "SshGroupAccessGranted" | "SshGroupAccessRevoked" => {
+                    let (Some(key), Some(value)) =
+                        (effect_string(effect, "key"), effect_string(effect, "value"))
+                    else {
+                        continue;
+                    };
+                    let tag = HostTag::new(key, value);
+                    for (label, tags) in &host_tags {
+                        if tags.contains(&tag) {
+                            affected.extend(hosts.get(label).cloned());
+                        }
+                    }
+                }
+                "SshHostRegistered" | "SshHostApproved" => {
+                    affected.extend(effect_string(effect, "hostname"));
+                }
+                // Removed devices can no longer be queried, so use the
+                // hosts their keys were last rendered into
+                "RoleAssigned" | "RoleRevoked" | "MemberRemoved"
+                | "SshLoginAllowed" | "SshLoginDisallowed" => {
+                    let user_id = effect_id(effect, "device_id").map(UserId::from);
+                    if let Some(hosts) = user_id.and_then(|id| rendered.get(&id)) {
+                        affected.extend(hosts.iter().cloned());
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(affected)
+    }
+    
+    /// Revoke the certificates of devices removed from the team or stripped
+    /// of `SSH_LABEL` or a host label
+    ///
+    /// A failed revocation is logged and does not stop the others.
+    async fn revoke_from_effects(&self, effects: &[VmEffect]) -> Result<()> {
+        let hosts = self.registered_hosts().await?;
+        for effect in effects {
+            let Some(user_id) = effect_id(effect, "device_id").map(UserId::from) else {
+                continue;
+            };
+            let hostname = match effect.name.as_str() {
+                "MemberRemoved" => None,
+                "LabelRevoked" => match effect_label(effect, "label") {
+                    Some(label) if label == SSH_LABEL => None,
+                    Some(label) => match hosts.get(&label) {
+                        Some(host) => Some(host.as_str()),
+                        None => continue,
+                    },
+                    None => continue,
+                },
+                _ => continue,
+            };
+            if let Err(e) = self.revoke_certificates(user_id, hostname).await {
+                eprintln!("Unable to revoke certificates of {}: {:?}", user_id, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Revoke a device's certificates on a host, or on every host for `None`
+    ///
+    /// Hosts the device still has access to, e.g. through a group grant, are
+    /// skipped. Every generation of the device's key ID is revoked, and the
+    /// generation bumped so certificates issued afterwards are accepted
+    /// wherever access is granted again.
+    ///
+    /// The revocations are recorded for every host before any KRL is
+    /// deployed. Hosts whose KRL fails to deploy are queued for the
+    /// reconciler and reported together once the others are done.
+    async fn revoke_certificates(&self, user_id: UserId, hostname: Option<&str>) -> Result<()> {
+        let device_id = user_id.to_string();
+        let mut generations = self.key_generations().await?;
+        let generation = generations.get(&device_id).copied().unwrap_or(0);
+        let now = SystemTime::now();
+
+        let mut revoked = Vec::new();
+        for host in self.inventory().await? {
+            if hostname.is_some_and(|h| h != host) {
+                continue;
+            }
+            if self.host_access(&host).await?.contains_key(&user_id) {
+                continue;
+            }
+            let mut revocations = self.certificate_revocations(&host).await?;
+            let mut changed = false;
+            for key_id in device_key_ids(&device_id, generation) {
+                changed |= revocations.revoke(&key_id, now);
+            }
+            if changed {
+                self.store_revocations(&host, &revocations).await?;
+                revoked.push(host);
+            }
+        }
+        if revoked.is_empty() {
+            return Ok(());
+        }
+        generations.insert(device_id, generation + 1);
+        write_atomic(&self.key_generations_file(), &serde_json::to_vec_pretty(&generations)?).await?;
+
+        let mut failed = Vec::new();
+        for host in revoked {
+            if let Err(e) = self.deploy_krl(&host).await {
+                failed.push(format!("{}: {:#}", host, e));
+                self.pending_hosts.lock().await.insert(host);
+            }
+        }
+        if !failed.is_empty() {
+            bail!(
+                "revoked the certificates of {}, but could not deploy the KRL to {}",
+                user_id,
+                failed.join("; ")
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Deploy a host's KRL
+    async fn deploy_krl(&self, hostname: &str) -> Result<()> {
+        let krl = self.certificate_revocations(hostname).await?.to_krl();
+        self.deploy_to_host(hostname, HostArtifact::Krl, krl).await
+    }
+
+    /// Store a host's certificate revocations along with its KRL
+    async fn store_revocations(&self, hostname: &str, revocations: &Revocations) -> Result<()> {
+        let contents = serde_json::to_vec_pretty(revocations)?;
+        write_atomic(&self.revocations_file(hostname), &contents).await?;
+        write_atomic(&self.keys_path.join(format!("{}.krl", hostname)), &revocations.to_krl()).await
+    }
+
+    /// Get the certificates revoked on a host
+    pub async fn certificate_revocations(&self, hostname: &str) -> Result<Revocations> {
+        let path = self.revocations_file(hostname);
+        match fs::read(&path).await {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .with_context(|| format!("invalid revocations in {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Revocations::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Path of a host's certificate revocations
+    fn revocations_file(&self, hostname: &str) -> PathBuf {
+        self.keys_path.join(format!("{}.revocations", hostname))
+    }
+
+    /// Get the current certificate generation of every device whose
+    /// certificates were ever revoked
+    async fn key_generations(&self) -> Result<BTreeMap<String, u64>> {
+        let path = self.key_generations_file();
+        match fs::read(&path).await {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .with_context(|| format!("invalid key generations in {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Path of the devices' certificate generations
+    fn key_generations_file(&self) -> PathBuf {
+        self.keys_path.join("key_generations")
+    }
+    
+    /// Update authorized_keys files for all hosts
+    async fn update_authorized_keys(&self) -> Result<()> {
+        for host in self.inventory().await? {
+            self.update_host_keys(&host).await?;
+        }
+        
+        Ok(())
+    }
+
+    /// Enroll the local device as an SSH host
+    ///
+    /// Must run on the host's own device, with its Ed25519 host key. The host
+    /// is not rendered for until an SSH admin approves it.
+    pub async fn enroll_host(&self, hostname: &str, host_key: &SshPublicKey) -> Result<()> {
+        validate_hostname(hostname)?;
+        let effects = self.client.actions(&self.graph_id)
+            .enroll_ssh_host(hostname.to_string(), host_key.clone())
+            .await?;
+        if !effects.iter().any(|e| e.name == "SshHostEnrolled") {
+            return Err(SshAccessError::MissingEffect("SshHostEnrolled").into());
+        }
+
+        Ok(())
+    }
+
+    /// Approve an enrolled host and render its keys
+    ///
+    /// The host's device is given `SSH_LABEL` so its agent can receive
+    /// updates, and `SSH_AUDIT_LABEL` to publish login records.
+    pub async fn approve_host(&self, hostname: &str) -> Result<Label> {
+        let enrollment = self.host_enrollment(hostname)
+            .await?
+            .with_context(|| format!("host {} is not enrolled", hostname))?;
+        if enrollment.approved {
+            bail!("host {} is already approved", hostname);
+        }
+
+        let host_label = self.register_host(hostname).await?;
+        self.client.actions(&self.graph_id)
+            .approve_ssh_host(hostname.to_string())
+            .await?;
+        let labels = self.device_labels(enrollment.host_device).await?;
+        if !labels.contains(&SSH_LABEL) {
+            self.client.actions(&self.graph_id)
+                .assign_label(enrollment.host_device, SSH_LABEL, ChanOp::Open)
+                .await?;
+        }
+        if !labels.contains(&SSH_AUDIT_LABEL) {
+            self.assign_audit_label(enrollment.host_device).await?;
+        }
+
+        self.update_host_keys(hostname).await?;
+
+        Ok(host_label)
+    }
+
+    /// Let a device collect the login audit records hosts publish
+    pub async fn add_audit_collector(&self, user_id: UserId) -> Result<()> {
+        self.assign_audit_label(user_id).await
+    }
+
+    /// Assign `SSH_AUDIT_LABEL` to a device
+    ///
+    /// Teams initialized before the label existed do not define it, so it is
+    /// defined when first assigning it fails.
+    async fn assign_audit_label(&self, user_id: UserId) -> Result<()> {
+        let assigned = self.client.actions(&self.graph_id)
+            .assign_label(user_id, SSH_AUDIT_LABEL, ChanOp::Open)
+            .await;
+        let Err(e) = assigned else {
+            return Ok(());
+        };
+        if let Err(define) = self.client.actions(&self.graph_id).define_label(SSH_AUDIT_LABEL).await {
+            return Err(e).with_context(|| format!("unable to assign the audit label, and defining it failed: {:#}", define));
+        }
+        self.client.actions(&self.graph_id)
+            .assign_label(user_id, SSH_AUDIT_LABEL, ChanOp::Open)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Remove a host from the inventory
+    ///
+    /// The host is sent empty keys first, for every login. A host that
+    /// cannot be reached is removed anyway, with the failure kept in its
+    /// deploy status. The host's label is retired, so grants and tags made
+    /// for it do not apply if the name is enrolled and approved again.
+    pub async fn decommission_host(&self, hostname: &str) -> Result<()> {
+        let enrollment = self.host_enrollment(hostname)
+            .await?
+            .with_context(|| format!("host {} is not enrolled", hostname))?;
+
+        let _ = self.deploy_to_host(hostname, HostArtifact::AuthorizedKeys, KEYS_HEADER.into()).await;
+        let _ = self.update_user_keys(hostname, &BTreeMap::new()).await;
+        let _ = self.deploy_to_host(hostname, HostArtifact::Sudoers, Sudoers::default().render().into_bytes()).await;
+
+        self.client.actions(&self.graph_id)
+            .decommission_ssh_host(hostname.to_string())
+            .await?;
+        let labels = self.device_labels(enrollment.host_device).await?;
+        for label in [SSH_LABEL, SSH_AUDIT_LABEL] {
+            if labels.contains(&label) {
+                self.client.actions(&self.graph_id)
+                    .revoke_label(enrollment.host_device, label)
+                    .await?;
+            }
+        }
+
+        self.record_rendered(hostname, &BTreeSet::new()).await;
+
+        Ok(())
+    }
+
+    /// List every enrolled host, approved or not
+    pub async fn host_enrollments(&self) -> Result<Vec<HostEnrollment>> {
+        let (_, effects) = self.client.actions(&self.graph_id)
+            .query_ssh_host_enrollments_off_graph()
+            .await?;
+
+        Ok(effects.iter().filter_map(effect_enrollment).collect())
+    }
+
+    /// Render `known_hosts` lines pinning every approved host's key
+    ///
+    /// Configured host certificate CAs are added as `@cert-authority` lines.
+    pub async fn known_hosts(&self) -> Result<KnownHosts> {
+        let mut known_hosts = KnownHosts::default();
+        for (pattern, key) in &self.host_cas {
+            known_hosts.add_cert_authority(pattern, key);
+        }
+        for host in self.host_enrollments().await? {
+            if host.approved {
+                known_hosts.add_host(&host.hostname, &host.host_key);
+            }
+        }
+
+        Ok(known_hosts)
+    }
+
+    /// Look up a host's enrollment
+    async fn host_enrollment(&self, hostname: &str) -> Result<Option<HostEnrollment>> {
+        Ok(self.host_enrollments()
+            .await?
+            .into_iter()
+            .find(|e| e.hostname == hostname))
+    }
+
+    /// List the approved hosts keys are rendered for
+    pub async fn inventory(&self) -> Result<Vec<String>> {
+        Ok(self.host_enrollments()
+            .await?
+            .into_iter()
+            .filter(|e| e.approved)
+            .map(|e| e.hostname)
+            .collect())
+    }
+    
+    /// Update authorized_keys for a specific host
+    ///
+    /// Hosts not in the inventory are skipped, so grants may be made before
+    /// a host is approved.
+    async fn update_host_keys(&self, hostname: &str) -> Result<()> {
+        if !self.host_enrollment(hostname).await?.is_some_and(|e| e.approved) {
+            return Ok(());
+        }
+
+        let rendered = self.render_host_keys(hostname, &[]).await?;
+
+        self.store_keys(hostname, &rendered.authorized_keys).await?;
+        
+        // Distribute keys to host
+        self.deploy_to_host(
+            hostname,
+            HostArtifact::AuthorizedKeys,
+            rendered.authorized_keys.into_bytes(),
+        )
+        .await?;
+        self.update_user_keys(hostname, &rendered.user_keys).await?;
+        for login in rendered.sudoers.withheld() {
+            eprintln!("No sudo rule for {} on {}: devices without admin access share the login", login, hostname);
+        }
+        let sudoers = rendered.sudoers.render();
+        write_atomic(&self.sudoers_file(hostname), sudoers.as_bytes()).await?;
+        self.deploy_to_host(hostname, HostArtifact::Sudoers, sudoers.into_bytes()).await?;
+        // sshd refuses keys outright while its KRL is missing, so it is
+        // deployed even when nothing is revoked
+        self.deploy_krl(hostname).await?;
+
+        self.record_rendered(hostname, &rendered.devices).await;
+        
+        Ok(())
+    }
+
+    /// Render the authorized_keys lines a host accepts for a login
+    ///
+    /// Served to `AuthorizedKeysCommand` on hosts, so each line carries the
+    /// same options as the files the manager deploys. Devices with login
+    /// mappings are only included for their mapped logins.
+    pub async fn host_keys(&self, hostname: &str, login: &str) -> Result<String> {
+        let mut rendered = self.render_host_keys(hostname, &[]).await?;
+        let mut keys = rendered.authorized_keys;
+        if let Some(user_keys) = rendered.user_keys.remove(login) {
+            keys.push_str(user_keys.trim_start_matches(KEYS_HEADER));
+        }
+        Ok(keys)
+    }
+
+    /// Write and deploy a host's per-login authorized_keys
+    ///
+    /// Logins that no longer have any keys are emptied on the host.
+    async fn update_user_keys(&self, hostname: &str, user_keys: &BTreeMap<String, String>) -> Result<()> {
+        let dir = self.user_keys_dir(hostname);
+        let mut stale = self.stored_logins(hostname).await?;
+        stale.retain(|login| !user_keys.contains_key(login));
+
+        for (login, keys) in user_keys {
+            write_atomic(&dir.join(login), keys.as_bytes()).await?;
+            self.deploy_to_host(hostname, HostArtifact::UserKeys(login.clone()), keys.clone().into_bytes())
+                .await?;
+        }
+        for login in stale {
+            self.deploy_to_host(hostname, HostArtifact::UserKeys(login.clone()), KEYS_HEADER.into())
+                .await?;
+            fs::remove_file(dir.join(&login)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Logins with per-login authorized_keys last written for a host
+    async fn stored_logins(&self, hostname: &str) -> Result<BTreeSet<String>> {
+        let mut logins = BTreeSet::new();
+        match fs::read_dir(self.user_keys_dir(hostname)).await {
+            Ok(mut entries) => {
+                while let Some(entry) = entries.next_entry().await? {
+                    let name = entry.file_name();
+                    if let Some(login) = name.to_str().filter(|l| validate_login(l).is_ok()) {
+                        logins.insert(login.to_string());
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(logins)
+    }
+
+    /// Render a host's authorized_keys files, sudoers fragment and the
+    /// devices they let in
+    ///
+    /// Mapped logins of SSH admins and of devices with an admin-level group
+    /// grant on the host may become root, unless another device that may use
+    /// the login has no such reason; see `Sudoers`. Devices without login
+    /// mappings never get a sudo rule. `changes` for the host are applied as
+    /// if they had been made.
+    async fn render_host_keys(
+        &self,
+        hostname: &str,
+        changes: &[AccessChange],
+    ) -> Result<RenderedKeys> {
+        let pending: Vec<_> = changes
+            .iter()
+            .filter(|c| c.hostname() == hostname)
+            .map(AccessChange::pending_grant)
+            .collect();
+        // Hosts without a registered label have no grants of their own
+        let host_label = self.lookup_host_label(hostname).await?;
+        let grants = self.effective_host_grants(host_label, &pending).await?;
+        let logins = self.login_principals().await?;
+        let tags = match host_label {
+            Some(label) => self.all_host_tags().await?.remove(&label).unwrap_or_default(),
+            None => BTreeSet::new(),
+        };
+        // Devices with an unexpired admin-level group grant on the host
+        let now = SystemTime::now();
+        let group_admins: BTreeSet<UserId> = match host_label {
+            Some(label) => self.expanded_group_grants()
+                .await?
+                .into_iter()
+                .filter(|(_, l, grant)| {
+                    *l == label && grant.level == SshAccessLevel::Admin && !grant.is_expired(now)
+                })
+                .map(|(user_id, _, _)| user_id)
+                .collect(),
+            None => BTreeSet::new(),
+        };
+
+        // Render one line per device with access, restricted to its grant,
+        // into the files of the logins it may use
+        let mut rendered = RenderedKeys {
+            authorized_keys: KEYS_HEADER.to_string(),
+            user_keys: BTreeMap::new(),
+            sudoers: Sudoers::default(),
+            devices: BTreeSet::new(),
+        };
+        for (user_id, grant) in &grants {
+            let key = self.device_ssh_key(*user_id).await?;
+            let line = authorized_keys_line(&key, grant)?;
+            let reason = if self.device_roles(*user_id).await?.contains(&SSH_ADMIN_ROLE) {
+                Some(SudoReason::SshAdmin)
+            } else if group_admins.contains(user_id) {
+                Some(SudoReason::GroupGrant)
+            } else {
+                None
+            };
+            match host_logins(logins.get(user_id), &tags) {
+                None => {
+                    // Only mapped logins can be named in a sudoers rule, but
+                    // the device may use any login that has one
+                    if reason.is_none() {
+                        rendered.sudoers.deny(None, *user_id);
+                    }
+                    rendered.authorized_keys.push_str(&line);
+                }
+                Some(allowed) => {
+                    for login in allowed {
+                        match reason {
+                            Some(reason) => rendered.sudoers.allow(&login, *user_id, reason),
+                            None => rendered.sudoers.deny(Some(&login), *user_id),
+                        }
+                        rendered.user_keys
+                            .entry(login)
+                            .or_insert_with(|| KEYS_HEADER.to_string())
+                            .push_str(&line);
+                    }
+                }
+            }
+            rendered.devices.insert(*user_id);
+        }
+
+        Ok(rendered)
+    }
+
+    /// Allow a device to log in as a unix account
+    ///
+    /// Once a device has any login mapping, it may only log in as its mapped
+    /// accounts on the hosts the mappings apply to.
+    pub async fn allow_login(&self, user_id: UserId, principal: &LoginPrincipal) -> Result<()> {
+        self.client.actions(&self.graph_id)
+            .allow_ssh_login(user_id, principal.login.clone(), principal.group.clone())
+            .await?;
+
+        self.update_device_hosts(user_id).await
+    }
+
+    /// Remove a login mapping from a device
+    pub async fn disallow_login(&self, user_id: UserId, principal: &LoginPrincipal) -> Result<()> {
+        self.client.actions(&self.graph_id)
+            .disallow_ssh_login(user_id, principal.login.clone(), principal.group.clone())
+            .await?;
+
+        self.update_device_hosts(user_id).await
+    }
+
+    /// Get the unix accounts a device may log in as on a host
+    ///
+    /// Returns `None` if the device has no login mappings and so may use any
+    /// account.
+    pub async fn allowed_logins(&self, user_id: UserId, hostname: &str) -> Result<Option<BTreeSet<String>>> {
+        let logins = self.login_principals().await?;
+        let tags = self.host_tags(hostname).await?;
+        Ok(host_logins(logins.get(&user_id), &tags))
+    }
+
+    /// Query every login mapping, keyed by device
+    async fn login_principals(&self) -> Result<BTreeMap<UserId, Vec<LoginPrincipal>>> {
+        let (_, effects) = self.client.actions(&self.graph_id)
+            .query_ssh_logins_off_graph()
+            .await?;
+
+        let mut logins: BTreeMap<UserId, Vec<LoginPrincipal>> = BTreeMap::new();
+        for effect in &effects {
+            let (Some(user_id), Some(login), Some(key), Some(value)) = (
+                effect_id(effect, "device_id"),
+                effect_string(effect, "login"),
+                effect_string(effect, "key"),
+                effect_string(effect, "value"),
+            ) else {
+                continue;
+            };
+            // An empty key applies on every host
+            let group = (!key.is_empty()).then(|| HostTag::new(key, value));
+            logins.entry(UserId::from(user_id)).or_default().push(LoginPrincipal { login, group });
+        }
+
+        Ok(logins)
+    }
+
+    /// Re-render every host a device's keys were last rendered into
+    async fn update_device_hosts(&self, user_id: UserId) -> Result<()> {
+        let hosts = self.rendered.lock().await
+            .get(&user_id)
+            .cloned()
+            .unwrap_or_default();
+        for host in hosts {
+            self.update_host_keys(&host).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Compute how `changes` would alter each host, without making them
+    ///
+    /// Each host's rendered authorized_keys files and sudoers fragment are
+    /// compared with the ones last written for it, so hosts out of date with
+    /// the team graph show up even without changes. Nothing is recorded in
+    /// the graph, written or deployed.
+    pub async fn plan(&self, changes: Vec<AccessChange>) -> Result<AccessPlan> {
+        let mut hostnames: BTreeSet<String> = self.inventory().await?.into_iter().collect();
+        hostnames.extend(changes.iter().map(|c| c.hostname().to_string()));
+
+        let mut hosts = BTreeMap::new();
+        for hostname in hostnames {
+            let stored = self.stored_logins(&hostname).await?;
+            let planned = self.render_host_keys(&hostname, &changes).await?.files(&stored);
+            let mut current = BTreeMap::new();
+            for artifact in planned.keys() {
+                let contents = self.stored_file(&hostname, artifact).await?.unwrap_or_default();
+                current.insert(artifact.clone(), contents);
+            }
+
+            let plan = diff_files(&current, &planned);
+            if !plan.is_empty() {
+                hosts.insert(hostname, plan);
+            }
+        }
+
+        Ok(AccessPlan { changes, hosts })
+    }
+
+    /// Carry out a plan
+    ///
+    /// Fails with `SshAccessError::StalePlan`, changing nothing, if the team
+    /// graph or the hosts' keys changed since the plan was made.
+    pub async fn apply(&self, plan: &AccessPlan) -> Result<()> {
+        if self.plan(plan.changes.clone()).await? != *plan {
+            return Err(SshAccessError::StalePlan.into());
+        }
+
+        for change in &plan.changes {
+            match change {
+                AccessChange::Grant { user_id, hostname, grant } => {
+                    self.grant_host_access(*user_id, hostname, grant.clone()).await?;
+                }
+                AccessChange::Revoke { user_id, hostname } => {
+                    self.revoke_host_access(*user_id, hostname).await?;
+                }
+            }
+        }
+
+        // Bring hosts no change touched up to date with the graph
+        for hostname in plan.hosts.keys() {
+            if !plan.changes.iter().any(|c| c.hostname() == hostname) {
+                self.update_host_keys(hostname).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compare each host's installed authorized_keys files and sudoers
+    /// fragment with the team graph
+    ///
+    /// Per-login files are audited for the logins the graph maps on the host
+    /// and those last written for it. With `remediate`, drifted hosts have
+    /// their files rewritten and redeployed from the graph. A host that cannot be audited or
+    /// remediated is recorded in the report's errors, and the rest are still
+    /// audited.
+    pub async fn audit_drift(&self, source: DriftSource, remediate: bool) -> Result<DriftReport> {
+        let mut report = DriftReport {
+            source,
+            hosts: BTreeMap::new(),
+            errors: BTreeMap::new(),
+        };
+
+        for hostname in self.inventory().await? {
+            match self.audit_host_drift(&hostname, source, remediate).await {
+                Ok(drift) if drift.is_clean() => {}
+                Ok(drift) => {
+                    report.hosts.insert(hostname, drift);
+                }
+                Err(err) => {
+                    report.errors.insert(hostname, format!("{:#}", err));
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Compare one host's installed files with the team graph, remediating
+    /// drift if asked to
+    async fn audit_host_drift(&self, hostname: &str, source: DriftSource, remediate: bool) -> Result<HostDrift> {
+        let stored = self.stored_logins(hostname).await?;
+        let expected = self.render_host_keys(hostname, &[]).await?.files(&stored);
+
+        let mut drift = HostDrift::default();
+        for (artifact, expected) in &expected {
+            let installed = match source {
+                DriftSource::KeysPath => self.stored_file(hostname, artifact).await?,
+                DriftSource::Deployed => self.deployer
+                    .fetch(hostname, artifact)
+                    .await
+                    .with_context(|| format!("unable to fetch {} from {}", artifact, hostname))?
+                    .map(String::from_utf8)
+                    .transpose()
+                    .with_context(|| format!("{} on {} is not UTF-8", artifact, hostname))?,
+            };
+            let file_drift = match artifact {
+                HostArtifact::Sudoers => FileDrift::compare_lines(installed.as_deref(), expected),
+                _ => FileDrift::compare(installed.as_deref(), expected),
+            };
+            drift.add(artifact, file_drift);
+        }
+
+        if !drift.is_clean() && remediate {
+            self.update_host_keys(hostname)
+                .await
+                .with_context(|| format!("unable to remediate {}", hostname))?;
+            drift.remediated = true;
+        }
+
+        Ok(drift)
+    }
+
+    /// Directory of a host's current per-login authorized_keys
+    fn user_keys_dir(&self, hostname: &str) -> PathBuf {
+        self.keys_path.join(format!("{}.users", hostname))
+    }
+
+    /// Path of a host's current sudoers fragment
+    fn sudoers_file(&self, hostname: &str) -> PathBuf {
+        self.keys_path.join(format!("{}.sudoers", hostname))
+    }
+
+    /// Read the copy of a rendered file last written for a host, `None` if
+    /// there is none
+    async fn stored_file(&self, hostname: &str, artifact: &HostArtifact) -> Result<Option<String>> {
+        let path = match artifact {
+            HostArtifact::AuthorizedKeys => keys_file_in(&self.keys_path, hostname),
+            HostArtifact::UserKeys(login) => self.user_keys_dir(hostname).join(login),
+            HostArtifact::Sudoers => self.sudoers_file(hostname),
+            _ => bail!("{} is not a rendered file", artifact),
+        };
+        match fs::read_to_string(&path).await {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("unable to read {}", path.display())),
+        }
+    }
+
+    /// Store rendered authorized_keys as a new generation and make it current
+    ///
+    /// Both files are replaced atomically, so a crash leaves the previous
+    /// keys in place rather than a truncated file.
+    async fn store_keys(&self, hostname: &str, authorized_keys: &str) -> Result<()> {
+        store_generation(&self.keys_path, hostname, authorized_keys, self.keep_generations).await
+    }
+
+    /// List the stored generations of a host's authorized_keys, oldest first
+    pub async fn keys_generations(&self, hostname: &str) -> Result<Vec<KeysGeneration>> {
+        list_generations(&self.keys_path, hostname).await
+    }
+
+    /// Restore a stored generation of a host's authorized_keys and deploy it
+    ///
+    /// The restored keys stay in place until the host is next rendered, e.g.
+    /// by the reconciler after a change affecting the host.
+    pub async fn rollback(&self, hostname: &str, generation: u64) -> Result<()> {
+        let contents = restore_generation(&self.keys_path, hostname, generation).await?;
+        self.deploy_to_host(hostname, HostArtifact::AuthorizedKeys, contents.clone().into_bytes())
+            .await?;
+
+        self.record_rendered(hostname, &keys_devices(contents.lines())).await;
+
+        Ok(())
+    }
+
+    /// Remember which devices were rendered into a host's keys
+    async fn record_rendered(&self, hostname: &str, devices: &BTreeSet<UserId>) {
+        let mut rendered = self.rendered.lock().await;
+        for hosts in rendered.values_mut() {
+            hosts.remove(hostname);
+        }
+        for user_id in devices {
+            rendered.entry(*user_id).or_default().insert(hostname.to_string());
+        }
+        rendered.retain(|_, hosts| !hosts.is_empty());
+    }
+
+    /// Get the OpenSSH public key of a team device
+    ///
+    /// The key is the device's Aranya signing key, so a device has a single
+    /// identity for both the team and SSH.
+    pub async fn device_ssh_key(&self, user_id: UserId) -> Result<SshPublicKey> {
+        let user_keys = self.device_keys(user_id).await?;
+        SshPublicKey::from_key_bundle(&user_keys, user_id.to_string())
+            .with_context(|| format!("invalid signing key for device {}", user_id))
+    }
+
+    /// Query the team for its member devices
+    async fn team_devices(&self) -> Result<BTreeSet<UserId>> {
+        let (_, effects) = self.client.actions(&self.graph_id)
+            .query_devices_on_team_off_graph()
+            .await?;
+
+        Ok(effects.iter().filter_map(|e| effect_id(e, "device_id")).map(UserId::from).collect())
+    }
+
+    /// Query the team for every device that holds `label`
+    async fn devices_with_label(&self, label: Label) -> Result<BTreeSet<UserId>> {
+        let mut devices = BTreeSet::new();
+        for user_id in self.team_devices().await? {
+            if self.device_labels(user_id).await?.contains(&label) {
+                devices.insert(user_id);
+            }
+        }
+
+        Ok(devices)
+    }
+
+    /// Query the team for the labels assigned to a device
+    async fn device_labels(&self, user_id: UserId) -> Result<BTreeSet<Label>> {
+        let (_, effects) = self.client.actions(&self.graph_id)
+            .query_label_assignments_off_graph(user_id.into())
+            .await?;
+
+        Ok(effects.iter().filter_map(|e| effect_label(e, "label")).collect())
+    }
+
+    /// Query the team for the roles held by a device
+    async fn device_roles(&self, user_id: UserId) -> Result<Vec<Role>> {
+        let (_, effects) = self.client.actions(&self.graph_id)
+            .query_device_role_off_graph(user_id.into())
+            .await?;
+
+        Ok(effects.iter().filter_map(|e| effect_role(e, "role")).collect())
+    }
+
+    /// Query the team for a device's public keys
+    async fn device_keys(&self, user_id: UserId) -> Result<KeyBundle> {
+        let (_, effects) = self.client.actions(&self.graph_id)
+            .query_device_keybundle_off_graph(user_id.into())
+            .await?;
+
+        effects.iter()
+            .find_map(|e| effect_key_bundle(e, "device_keys"))
+            .with_context(|| format!("no key bundle for device {}", user_id))
+    }
+    
+    /// Deliver a rendered file to a host and record the outcome
+    async fn deploy_to_host(&self, hostname: &str, artifact: HostArtifact, contents: Vec<u8>) -> Result<()> {
+        let update = HostUpdate {
+            hostname: hostname.to_string(),
+            artifact,
+            contents,
+        };
+        let result = self.deployer.deploy(&update).await;
+
+        let status = DeployStatus {
+            artifact: update.artifact.clone(),
+            attempted_at: SystemTime::now(),
+            result: result.as_ref().copied().map_err(|e| format!("{:#}", e)),
+        };
+        self.deploy_status.lock().await
+            .entry(hostname.to_string())
+            .or_default()
+            .insert(update.artifact.clone(), status);
+
+        result.with_context(|| {
+            format!("{} backend failed to deploy {} to {}", self.deployer.name(), update.artifact, hostname)
+        })?;
+        Ok(())
+    }
+
+    /// Get the last deployment status of each file on a host
+    pub async fn deploy_status(&self, hostname: &str) -> BTreeMap<HostArtifact, DeployStatus> {
+        self.deploy_status.lock().await
+            .get(hostname)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// List the hosts whose last deployment of any file failed
+    pub async fn failed_deployments(&self) -> Vec<String> {
+        self.deploy_status.lock().await
+            .iter()
+            .filter(|(_, statuses)| statuses.values().any(|s| !s.is_ok()))
+            .map(|(host, _)| host.clone())
+            .collect()
+    }
+}
+
+/// The lowest host label value past every allocated label, and no lower
+/// than `candidate`
+fn next_host_label(allocated: &BTreeMap<Label, String>, candidate: u32) -> u32 {
+    allocated
+        .keys()
+        .map(|label| label.to_u32() + 1)
+        .max()
+        .unwrap_or(HOST_LABEL_BASE)
+        .max(candidate)
+}
+
+/// Path of a host's current authorized_keys in `keys_path`
+fn keys_file_in(keys_path: &Path, hostname: &str) -> PathBuf {
+    keys_path.join(format!("{}.keys", hostname))
+}
+
+/// Path of a stored generation of a host's authorized_keys in `keys_path`
+fn generation_file_in(keys_path: &Path, hostname: &str, generation: u64) -> PathBuf {
+    keys_path.join(format!("{}.keys.{}", hostname, generation))
+}
+
+/// Store authorized_keys in `keys_path` as a new generation and make it
+/// current, keeping at most `keep` generations
+///
+/// Unchanged keys do not start a new generation.
+async fn store_generation(keys_path: &Path, hostname: &str, authorized_keys: &str, keep: usize) -> Result<()> {
+    let keys_file = keys_file_in(keys_path, hostname);
+    if fs::read_to_string(&keys_file).await.ok().as_deref() == Some(authorized_keys) {
+        return Ok(());
+    }
+
+    let generations = list_generations(keys_path, hostname).await?;
+    let next = generations.last().map_or(1, |g| g.generation + 1);
+    write_atomic(&generation_file_in(keys_path, hostname, next), authorized_keys.as_bytes()).await?;
+    write_atomic(&keys_file, authorized_keys.as_bytes()).await?;
+
+    // Drop the oldest generations beyond the ones kept
+    let excess = (generations.len() + 1).saturating_sub(keep);
+    for old in &generations[..excess] {
+        fs::remove_file(generation_file_in(keys_path, hostname, old.generation)).await?;
+    }
+
+    Ok(())
+}
+
+/// List the generations of a host's authorized_keys in `keys_path`, oldest
+/// first
+async fn list_generations(keys_path: &Path, hostname: &str) -> Result<Vec<KeysGeneration>> {
+    let prefix = format!("{}.keys.", hostname);
+    let mut generations = Vec::new();
+    let mut entries = fs::read_dir(keys_path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let Some(generation) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(&prefix))
+            .and_then(|n| n.parse::<u64>().ok())
+        else {
+            continue;
+        };
+        generations.push(KeysGeneration {
+            generation,
+            written_at: entry.metadata().await?.modified()?,
+        });
+    }
+    generations.sort_by_key(|g| g.generation);
+
+    Ok(generations)
+}
+
+/// Make a stored generation of a host's authorized_keys in `keys_path`
+/// current again, returning its contents
+async fn restore_generation(keys_path: &Path, hostname: &str, generation: u64) -> Result<String> {
+    let contents = match fs::read_to_string(generation_file_in(keys_path, hostname, generation)).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(SshAccessError::UnknownGeneration {
+                hostname: hostname.to_string(),
+                generation,
+            }
+            .into());
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    write_atomic(&keys_file_in(keys_path, hostname), contents.as_bytes()).await?;
+    Ok(contents)
+}
+
+/// Parse a device id as carried in a signer chain
+fn parse_user_id(s: &str) -> Result<UserId> {
+    s.parse().with_context(|| format!("invalid device id `{}`", s))
+}
+
+/// Merge a grant into a set of effective grants, ignoring expired grants
+fn merge_grant<K: Ord>(grants: &mut BTreeMap<K, SshGrant>, key: K, grant: SshGrant, now: SystemTime) {
+    if grant.is_expired(now) {
+        return;
+    }
+    match grants.get_mut(&key) {
+        Some(existing) if !existing.is_expired(now) => {
+            *existing = existing.clone().broadest(grant);
+        }
+        _ => {
+            grants.insert(key, grant);
+        }
+    }
+}
+
+/// Compare a host's current and planned files line by line
+fn diff_files(current: &BTreeMap<HostArtifact, String>, planned: &BTreeMap<HostArtifact, String>) -> HostPlan {
+    let lines = |contents: Option<&String>| -> BTreeSet<String> {
+        contents
+            .into_iter()
+            .flat_map(|c| c.lines())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(String::from)
+            .collect()
+    };
+    // Devices are only named by key files
+    let devices = |files: &BTreeMap<HostArtifact, String>| {
+        keys_devices(
+            files
+                .iter()
+                .filter(|(artifact, _)| **artifact != HostArtifact::Sudoers)
+                .flat_map(|(_, contents)| contents.lines()),
+        )
+    };
+
+    let mut plan = HostPlan::default();
+    for artifact in current.keys().chain(planned.keys()).collect::<BTreeSet<_>>() {
+        let (was, will) = (lines(current.get(artifact)), lines(planned.get(artifact)));
+        let added: Vec<_> = will.difference(&was).cloned().collect();
+        let removed: Vec<_> = was.difference(&will).cloned().collect();
+        if !added.is_empty() {
+            plan.added.insert(artifact.clone(), added);
+        }
+        if !removed.is_empty() {
+            plan.removed.insert(artifact.clone(), removed);
+        }
+    }
+    let (current_devices, planned_devices) = (devices(current), devices(planned));
+    plan.gained = planned_devices.difference(&current_devices).copied().collect();
+    plan.lost = current_devices.difference(&planned_devices).copied().collect();
+
+    plan
+}
+
+/// Resolve the logins a device may use on a host with `tags`
+///
+/// `None` means the device has no mappings and may use any login.
+fn host_logins(principals: Option<&Vec<LoginPrincipal>>, tags: &BTreeSet<HostTag>) -> Option<BTreeSet<String>> {
+    let principals = principals?;
+    Some(principals.iter()
+        .filter(|p| p.applies_to(tags.iter()))
+        .map(|p| p.login.clone())
+        .collect())
+}
+
+/// Collect the devices of authorized_keys lines from their key comments
+fn keys_devices<'a>(lines: impl Iterator<Item = &'a str>) -> BTreeSet<UserId> {
+    lines
+        .filter_map(|line| line.parse::<SshPublicKey>().ok())
+        .filter_map(|key| key.comment().parse::<UserId>().ok())
+        .collect()
+}
+
+/// Format an `authorized_keys` line enforcing a grant
+fn authorized_keys_line(key: &SshPublicKey, grant: &SshGrant) -> Result<String> {
+    let options = grant.authorized_keys_options()?;
+    Ok(if options.is_empty() {
+        format!("{}\n", key)
+    } else {
+        format!("{} {}\n", options.join(","), key)
+    })
+}
+
+/// Look up a field by name in an effect
+fn effect_field<'a>(effect: &'a VmEffect, key: &str) -> Option<&'a Value> {
+    effect.fields.iter().find(|kv| kv.key() == key).map(|kv| kv.value())
+}
+
+/// Read an id field from an effect
+fn effect_id(effect: &VmEffect, key: &str) -> Option<Id> {
+    match effect_field(effect, key)? {
+        Value::Id(id) => Some(*id),
+        _ => None,
+    }
+}
+
+/// Read a string field from an effect
+fn effect_string(effect: &VmEffect, key: &str) -> Option<String> {
+    match effect_field(effect, key)? {
+        Value::String(s) => Some(s.clone()),
+        _ => None,
+    }
+}
+
+/// Read a host enrollment from a `QuerySshHostEnrollmentResult` effect
+fn effect_enrollment(effect: &VmEffect) -> Option<HostEnrollment> {
+    let hostname = effect_hostname(effect, "hostname")?;
+    let host_key = effect_string(effect, "host_key")?.parse().ok()?;
+    let approved = match effect_field(effect, "approved")? {
+        Value::Bool(b) => *b,
+        _ => return None,
+    };
+    Some(HostEnrollment {
+        host_device: UserId::from(effect_id(effect, "host_device")?),
+        hostname,
+        host_key,
+        approved,
+    })
+}
+
+/// Read a hostname field from an effect
+///
+/// Hostnames recorded before the policy checked them are dropped, since
+/// they are used in file names.
+fn effect_hostname(effect: &VmEffect, key: &str) -> Option<String> {
+    effect_string(effect, key).filter(|h| validate_hostname(h).is_ok())
+}
+
+/// Read a label field from an effect
+fn effect_label(effect: &VmEffect, key: &str) -> Option<Label> {
+    match effect_field(effect, key)? {
+        Value::Int(n) => u32::try_from(*n).ok().map(Label::new),
+        _ => None,
+    }
+}
+
+/// Read a role field from an effect
+fn effect_role(effect: &VmEffect, key: &str) -> Option<Role> {
+    match effect_field(effect, key)? {
+        Value::Int(n) => Role::try_from(*n).ok(),
+        _ => None,
+    }
+}
+
+/// Read a grant from a `QuerySshGrantResult` effect
+fn effect_grant(effect: &VmEffect) -> Option<(UserId, Label, SshGrant)> {
+    let user_id = UserId::from(effect_id(effect, "device_id")?);
+    let host_label = effect_label(effect, "host_label")?;
+    Some((user_id, host_label, effect_ssh_grant(effect)?))
+}
+
+/// Read the level, command, source and expiry fields of a grant effect
+fn effect_ssh_grant(effect: &VmEffect) -> Option<SshGrant> {
+    let level = match effect_field(effect, "level")? {
+        Value::Int(n) => *n,
+        _ => return None,
+    };
+    let command = effect_string(effect, "command").unwrap_or_default();
+    let level = SshAccessLevel::from_policy(level, command).ok()?;
+    let from = effect_string(effect, "from")
+        .map(|from| from.split(',').filter(|f| !f.is_empty()).map(String::from).collect())
+        .unwrap_or_default();
+    let expires_at = match effect_field(effect, "expires_at") {
+        Some(Value::Int(n)) => SshGrant::expiry_from_policy(*n),
+        _ => None,
+    };
+    // Grants recorded by other clients are not trusted to be renderable
+    let grant = SshGrant { level, from, expires_at };
+    grant.validate().ok()?;
+    Some(grant)
+}
+
+/// Read an access request from a `QuerySshAccessRequestResult` effect
+///
+/// Requests for hosts missing from `hosts` have an empty hostname.
+fn effect_access_request(effect: &VmEffect, hosts: &BTreeMap<Label, String>) -> Option<AccessRequest> {
+    let Value::Int(level) = effect_field(effect, "level")? else {
+        return None;
+    };
+    let Value::Int(duration) = effect_field(effect, "duration")? else {
+        return None;
+    };
+    let command = effect_string(effect, "command").unwrap_or_default();
+    let host_label = effect_label(effect, "host_label")?;
+    Some(AccessRequest {
+        request_id: effect_id(effect, "request_id")?,
+        user_id: UserId::from(effect_id(effect, "device_id")?),
+        hostname: hosts.get(&host_label).cloned().unwrap_or_default(),
+        level: SshAccessLevel::from_policy(*level, command).ok()?,
+        duration: Duration::from_secs(u64::try_from(*duration).unwrap_or(0)),
+        reason: effect_string(effect, "reason").unwrap_or_default(),
+    })
+}
+
+/// Read a `KeyBundle` struct field from an effect
+fn effect_key_bundle(effect: &VmEffect, key: &str) -> Option<KeyBundle> {
+    let Value::Struct(s) = effect_field(effect, key)? else {
+        return None;
+    };
+    let bytes = |name: &str| match s.fields.get(name) {
+        Some(Value::Bytes(b)) => Some(b.clone()),
+        _ => None,
+    };
+    Some(KeyBundle {
+        ident_key: bytes("ident_key")?,
+        sign_key: bytes("sign_key")?,
+        enc_key: bytes("enc_key")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aranya_policy_vm::KVPair;
+
+    #[test]
+    fn first_host_label_is_the_base() {
+        assert_eq!(next_host_label(&BTreeMap::new(), HOST_LABEL_BASE), HOST_LABEL_BASE);
+    }
+
+    #[test]
+    fn host_labels_skip_registered_and_taken_values() {
+        let registered = BTreeMap::from([
+            (Label::new(HOST_LABEL_BASE), "db1".to_string()),
+            (Label::new(HOST_LABEL_BASE + 4), "db2".to_string()),
+        ]);
+        assert_eq!(next_host_label(&registered, HOST_LABEL_BASE), HOST_LABEL_BASE + 5);
+        // A value a previous attempt found taken is not retried
+        assert_eq!(next_host_label(&registered, HOST_LABEL_BASE + 9), HOST_LABEL_BASE + 9);
+    }
+
+    fn effect(name: &str, fields: Vec<(&str, Value)>) -> VmEffect {
+        VmEffect {
+            name: name.to_string(),
+            fields: fields.into_iter().map(|(k, v)| KVPair::new(k, v)).collect(),
+            command: Default::default(),
+            recalled: false,
+        }
+    }
+
+    fn access_request_effect(level: i64, duration: i64) -> VmEffect {
+        effect(
+            "QuerySshAccessRequestResult",
+            vec![
+                ("request_id", Value::Id(Id::default())),
+                ("device_id", Value::Id(Id::default())),
+                ("host_label", Value::Int(i64::from(HOST_LABEL_BASE))),
+                ("level", Value::Int(level)),
+                ("command", Value::String("uptime".to_string())),
+                ("duration", Value::Int(duration)),
+                ("reason", Value::String("incident 42".to_string())),
+            ],
+        )
+    }
+
+    #[test]
+    fn access_requests_read_from_effects() {
+        let hosts = BTreeMap::from([(Label::new(HOST_LABEL_BASE), "db1".to_string())]);
+        let request = effect_access_request(&access_request_effect(1, 3600), &hosts).unwrap();
+        assert_eq!(request.hostname, "db1");
+        assert_eq!(request.level, SshAccessLevel::ReadOnly { command: "uptime".to_string() });
+        assert_eq!(request.duration, Duration::from_secs(3600));
+        assert_eq!(request.reason, "incident 42");
+
+        // Hosts decommissioned since the request have no name
+        let request = effect_access_request(&access_request_effect(2, -1), &BTreeMap::new()).unwrap();
+        assert_eq!(request.hostname, "");
+        assert_eq!(request.duration, Duration::ZERO);
+
+        assert!(effect_access_request(&access_request_effect(9, 3600), &hosts).is_none());
+    }
+
+    #[test]
+    fn rollback_failure_reports_both_errors() {
+        let user_id = UserId::default();
+        let cause = anyhow::anyhow!("label not defined").context("unable to assign label");
+        let err = SshAccessError::rollback_failed("add", user_id, cause, "daemon unreachable");
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "unable to add device {}: unable to assign label: label not defined; \
+                 rollback also failed: daemon unreachable",
+                user_id
+            ),
+        );
+    }
+
+    /// A fresh directory for a test's key files
+    async fn keys_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("aranya-ssh-keys-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir).await;
+        fs::create_dir_all(&dir).await.unwrap();
+        dir
+    }
+
+    async fn generation_numbers(dir: &Path, hostname: &str) -> Vec<u64> {
+        list_generations(dir, hostname)
+            .await
+            .unwrap()
+            .iter()
+            .map(|g| g.generation)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn store_keys_keeps_the_newest_generations() {
+        let dir = keys_dir("store").await;
+        for keys in ["a\n", "a\n", "b\n", "c\n"] {
+            store_generation(&dir, "db1", keys, 2).await.unwrap();
+        }
+        store_generation(&dir, "db2", "x\n", 2).await.unwrap();
+
+        // The repeated render did not start a generation
+        assert_eq!(generation_numbers(&dir, "db1").await, [2, 3]);
+        assert_eq!(fs::read_to_string(keys_file_in(&dir, "db1")).await.unwrap(), "c\n");
+        assert_eq!(fs::read_to_string(generation_file_in(&dir, "db1", 2)).await.unwrap(), "b\n");
+        assert_eq!(generation_numbers(&dir, "db2").await, [1]);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rollback_restores_a_stored_generation() {
+        let dir = keys_dir("rollback").await;
+        store_generation(&dir, "db1", "a\n", 10).await.unwrap();
+        store_generation(&dir, "db1", "b\n", 10).await.unwrap();
+
+        assert_eq!(restore_generation(&dir, "db1", 1).await.unwrap(), "a\n");
+        assert_eq!(fs::read_to_string(keys_file_in(&dir, "db1")).await.unwrap(), "a\n");
+        // Restoring does not add or remove generations
+        assert_eq!(generation_numbers(&dir, "db1").await, [1, 2]);
+
+        let err = restore_generation(&dir, "db1", 3).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SshAccessError>(),
+            Some(SshAccessError::UnknownGeneration { generation: 3, .. })
+        ));
+        assert_eq!(fs::read_to_string(keys_file_in(&dir, "db1")).await.unwrap(), "a\n");
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[test]
+    fn host_logins_follow_group_tags() {
+        let prod = HostTag::new("env", "prod");
+        let principals = vec![
+            LoginPrincipal::new("deploy", Some(prod.clone())).unwrap(),
+            LoginPrincipal::new("alice", None).unwrap(),
+        ];
+
+        assert_eq!(host_logins(None, &BTreeSet::from([prod.clone()])), None);
+        assert_eq!(
+            host_logins(Some(&principals), &BTreeSet::from([prod])),
+            Some(BTreeSet::from(["alice".to_string(), "deploy".to_string()]))
+        );
+        assert_eq!(
+            host_logins(Some(&principals), &BTreeSet::from([HostTag::new("env", "dev")])),
+            Some(BTreeSet::from(["alice".to_string()]))
+        );
+    }
+
+    #[test]
+    fn diff_files_reports_lines_per_file() {
+        let bob = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIJlBEpoc5Ej7EFqINpQMGVHMzVX/XlSLf09p21MF+w3i bob";
+        let current = BTreeMap::from([(HostArtifact::AuthorizedKeys, format!("{}{}\n", KEYS_HEADER, bob))]);
+        let deploy = HostArtifact::UserKeys("deploy".to_string());
+        let planned = BTreeMap::from([
+            (HostArtifact::AuthorizedKeys, KEYS_HEADER.to_string()),
+            (deploy.clone(), format!("{}{}\n", KEYS_HEADER, bob)),
+        ]);
+
+        let plan = diff_files(&current, &planned);
+        assert_eq!(plan.removed, BTreeMap::from([(HostArtifact::AuthorizedKeys, vec![bob.to_string()])]));
+        assert_eq!(plan.added, BTreeMap::from([(deploy, vec![bob.to_string()])]));
+        assert!(diff_files(&current, &current).is_empty());
+    }
+}
@@ -0,0 +1,326 @@
+//! Login audit records parsed from sshd logs.
+//!
+//! Hosts publish a record for every accepted or failed login, with the team
+//! device the key belongs to, so actual use can be correlated with the grants
+//! recorded in the graph. Records are sent to a collector device over AFC
+//! channels on `SSH_AUDIT_LABEL`.
+
+use std::{collections::BTreeMap, time::SystemTime};
+
+use anyhow::{Context, Result};
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+
+/// Whether sshd let a login in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LoginOutcome {
+    Accepted,
+    Failed,
+}
+
+/// A login attempt as logged by sshd.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoginEvent {
+    pub outcome: LoginOutcome,
+    /// Authentication method, e.g. `publickey` or `password`.
+    pub method: String,
+    /// The unix account the client asked for.
+    pub login: String,
+    /// Set when the account does not exist on the host.
+    pub invalid_user: bool,
+    pub source: String,
+    pub port: u16,
+    /// SHA256 fingerprint of the client's key, for key and certificate
+    /// logins.
+    pub fingerprint: Option<String>,
+    /// Key ID of the client's certificate, naming the device for
+    /// certificates issued by the team CA.
+    pub key_id: Option<String>,
+    /// SHA256 fingerprint of the CA that signed the client's certificate.
+    /// The key ID only names a device if this is the team CA.
+    pub ca_fingerprint: Option<String>,
+}
+
+/// A login attempt attributed to a team device.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub hostname: String,
+    /// When sshd logged the attempt, if the log carries a full timestamp.
+    pub logged_at: Option<SystemTime>,
+    /// When the attempt was read from the log.
+    pub observed_at: SystemTime,
+    pub event: LoginEvent,
+    /// The device holding the key, `None` if it is not a team device's.
+    pub device_id: Option<String>,
+}
+
+impl AuditRecord {
+    /// Encode the record for sending to the collector.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        postcard::to_allocvec(self).context("unable to encode audit record")
+    }
+
+    /// Decode a record received from a host.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        postcard::from_bytes(bytes).context("unable to decode audit record")
+    }
+}
+
+/// Parse an sshd `Accepted ...` or `Failed ...` message.
+///
+/// Other messages return `None`. Key and certificate logins are logged as
+/// e.g. `Accepted publickey for alice from 10.0.0.5 port 51234 ssh2: ED25519
+/// SHA256:... ID <key id> (serial 7) CA ED25519 SHA256:...`.
+pub fn parse_sshd_message(message: &str) -> Option<LoginEvent> {
+    let mut words = message.split_whitespace();
+    let outcome = match words.next()? {
+        "Accepted" => LoginOutcome::Accepted,
+        "Failed" => LoginOutcome::Failed,
+        _ => return None,
+    };
+    let method = words.next()?.to_string();
+    expect(&mut words, "for")?;
+    let mut login = words.next()?;
+    let invalid_user = login == "invalid";
+    if invalid_user {
+        expect(&mut words, "user")?;
+        login = words.next()?;
+    }
+    expect(&mut words, "from")?;
+    let source = words.next()?.to_string();
+    expect(&mut words, "port")?;
+    let port = words.next()?.parse().ok()?;
+
+    // `ssh2:` is followed by the key type and fingerprint for key logins
+    let mut fingerprint = None;
+    let mut key_id = None;
+    let mut ca_fingerprint = None;
+    if words.next().is_some_and(|proto| proto.ends_with(':')) {
+        let _key_type = words.next();
+        fingerprint = words.next().filter(|f| f.starts_with("SHA256:")).map(str::to_string);
+        // The key ID is logged as is, so the CA is taken from the end where
+        // the certificate holder cannot put it
+        let rest: Vec<&str> = words.collect();
+        if let ["ID", cert @ .., "(serial", _serial, "CA", _ca_type, ca] = rest.as_slice() {
+            if ca.starts_with("SHA256:") {
+                key_id = Some(cert.join(" "));
+                ca_fingerprint = Some(ca.to_string());
+            }
+        }
+    }
+
+    Some(LoginEvent {
+        outcome,
+        method,
+        login: login.to_string(),
+        invalid_user,
+        source,
+        port,
+        fingerprint,
+        key_id,
+        ca_fingerprint,
+    })
+}
+
+/// Split a syslog line into its timestamp, if it is a full RFC 3339 one, and
+/// the sshd message.
+///
+/// Lines from other programs return `None`. The program name is whatever the
+/// sender claimed, so any local user can forge sshd lines, e.g. with
+/// `logger -t sshd`. The journal records who actually logged an entry, so
+/// prefer it where available.
+pub fn parse_syslog_line(line: &str) -> Option<(Option<SystemTime>, &str)> {
+    let (prefix, message) = line.split_once(": ")?;
+    let program = prefix.split_whitespace().last()?;
+    if program != "sshd" && !program.starts_with("sshd[") {
+        return None;
+    }
+    let logged_at = prefix
+        .split_whitespace()
+        .next()
+        .and_then(|ts| DateTime::parse_from_rfc3339(ts).ok())
+        .map(SystemTime::from);
+    Some((logged_at, message))
+}
+
+/// An entry of a `journalctl -o export` stream.
+pub type JournalEntry = BTreeMap<String, String>;
+
+/// Parse the next complete entry from the front of a journal export stream.
+///
+/// Returns the entry and the number of bytes it took, or `None` if the
+/// buffer ends before the entry does. Binary fields are decoded lossily.
+pub fn next_journal_entry(buf: &[u8]) -> Option<(JournalEntry, usize)> {
+    let mut entry = JournalEntry::new();
+    let mut pos = 0;
+    loop {
+        let line_end = pos + buf[pos..].iter().position(|&b| b == b'\n')?;
+        let line = &buf[pos..line_end];
+        pos = line_end + 1;
+        // A blank line ends the entry
+        if line.is_empty() {
+            return Some((entry, pos));
+        }
+
+        match line.iter().position(|&b| b == b'=') {
+            Some(eq) => {
+                entry.insert(
+                    String::from_utf8_lossy(&line[..eq]).into_owned(),
+                    String::from_utf8_lossy(&line[eq + 1..]).into_owned(),
+                );
+            }
+            // Binary field: the name, then a little-endian length, the
+            // data and a newline
+            None => {
+                let len_bytes = buf.get(pos..pos + 8)?;
+                let len = u64::from_le_bytes(len_bytes.try_into().ok()?) as usize;
+                let data = buf.get(pos + 8..pos + 8 + len)?;
+                buf.get(pos + 8 + len)?;
+                entry.insert(
+                    String::from_utf8_lossy(line).into_owned(),
+                    String::from_utf8_lossy(data).into_owned(),
+                );
+                pos += 8 + len + 1;
+            }
+        }
+    }
+}
+
+/// Get the timestamp and sshd message of a journal entry.
+///
+/// Entries from other programs return `None`. The sender sets
+/// `SYSLOG_IDENTIFIER` itself, so entries are attributed by the `_COMM` and
+/// `_SYSTEMD_UNIT` fields journald adds instead: a process running as sshd
+/// in sshd's unit. Users' sessions run in their own scopes.
+pub fn parse_journal_entry(entry: &JournalEntry) -> Option<(Option<SystemTime>, &str)> {
+    let comm = entry.get("_COMM")?;
+    let unit = entry.get("_SYSTEMD_UNIT")?;
+    if !matches!(comm.as_str(), "sshd" | "sshd-session") || !is_sshd_unit(unit) {
+        return None;
+    }
+    let logged_at = entry
+        .get("__REALTIME_TIMESTAMP")
+        .and_then(|us| us.parse::<u64>().ok())
+        .map(|us| SystemTime::UNIX_EPOCH + std::time::Duration::from_micros(us));
+    Some((logged_at, entry.get("MESSAGE")?))
+}
+
+/// Whether `unit` is sshd's service, or an instance of its socket-activated
+/// one.
+fn is_sshd_unit(unit: &str) -> bool {
+    let Some(name) = unit.strip_suffix(".service") else {
+        return false;
+    };
+    let service = name.split_once('@').map_or(name, |(service, _)| service);
+    service == "ssh" || service == "sshd"
+}
+
+/// Consume the next word if it is `word`.
+fn expect<'a>(words: &mut impl Iterator<Item = &'a str>, word: &str) -> Option<()> {
+    (words.next()? == word).then_some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_key_login() {
+        let event = parse_sshd_message(
+            "Accepted publickey for alice from 10.0.0.5 port 51234 ssh2: ED25519 SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s",
+        )
+        .unwrap();
+        assert_eq!(event.outcome, LoginOutcome::Accepted);
+        assert_eq!(event.method, "publickey");
+        assert_eq!(event.login, "alice");
+        assert!(!event.invalid_user);
+        assert_eq!(event.source, "10.0.0.5");
+        assert_eq!(event.port, 51234);
+        assert_eq!(
+            event.fingerprint.as_deref(),
+            Some("SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s")
+        );
+        assert_eq!(event.key_id, None);
+        assert_eq!(event.ca_fingerprint, None);
+    }
+
+    #[test]
+    fn parses_failed_login() {
+        let event = parse_sshd_message("Failed password for root from 192.0.2.7 port 4022 ssh2").unwrap();
+        assert_eq!(event.outcome, LoginOutcome::Failed);
+        assert_eq!(event.method, "password");
+        assert_eq!(event.login, "root");
+        assert_eq!(event.fingerprint, None);
+    }
+
+    #[test]
+    fn parses_invalid_user() {
+        let event = parse_sshd_message("Failed password for invalid user admin from 192.0.2.7 port 4022 ssh2").unwrap();
+        assert!(event.invalid_user);
+        assert_eq!(event.login, "admin");
+        assert_eq!(event.source, "192.0.2.7");
+    }
+
+    #[test]
+    fn parses_certificate_login() {
+        let event = parse_sshd_message(
+            "Accepted publickey for deploy from 10.0.0.5 port 51234 ssh2: ED25519-CERT SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s ID device-a/2 (serial 7) CA ED25519 SHA256:Fb0dVQkOH5sLMzBvqkXL9EhFMVXWdvkObFdrYw6jSOM",
+        )
+        .unwrap();
+        assert_eq!(event.key_id.as_deref(), Some("device-a/2"));
+        assert_eq!(
+            event.ca_fingerprint.as_deref(),
+            Some("SHA256:Fb0dVQkOH5sLMzBvqkXL9EhFMVXWdvkObFdrYw6jSOM")
+        );
+    }
+
+    #[test]
+    fn takes_ca_from_the_end() {
+        // A key ID made to look like the rest of the line
+        let event = parse_sshd_message(
+            "Accepted publickey for deploy from 10.0.0.5 port 51234 ssh2: ED25519-CERT SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s ID device-a (serial 1) CA ED25519 SHA256:team (serial 7) CA ED25519 SHA256:other",
+        )
+        .unwrap();
+        assert_eq!(event.key_id.as_deref(), Some("device-a (serial 1) CA ED25519 SHA256:team"));
+        assert_eq!(event.ca_fingerprint.as_deref(), Some("SHA256:other"));
+    }
+
+    #[test]
+    fn ignores_other_messages() {
+        assert_eq!(parse_sshd_message("Connection closed by 192.0.2.7 port 4022"), None);
+        assert_eq!(parse_sshd_message("Accepted publickey for alice"), None);
+    }
+
+    #[test]
+    fn reads_text_and_binary_journal_fields() {
+        let mut buf = b"_COMM=sshd\nMESSAGE\n".to_vec();
+        let message = b"Accepted publickey\nfor alice";
+        buf.extend_from_slice(&(message.len() as u64).to_le_bytes());
+        buf.extend_from_slice(message);
+        buf.extend_from_slice(b"\n\nMESSAGE=next\n");
+
+        let (entry, len) = next_journal_entry(&buf).unwrap();
+        assert_eq!(entry["_COMM"], "sshd");
+        assert_eq!(entry["MESSAGE"], "Accepted publickey\nfor alice");
+        // The second entry is not finished yet
+        assert_eq!(next_journal_entry(&buf[len..]), None);
+        assert_eq!(next_journal_entry(&buf[..len - 3]), None);
+    }
+
+    #[test]
+    fn attributes_journal_entries_by_trusted_fields() {
+        let entry = |comm: &str, unit: &str| {
+            JournalEntry::from([
+                ("_COMM".to_string(), comm.to_string()),
+                ("_SYSTEMD_UNIT".to_string(), unit.to_string()),
+                ("SYSLOG_IDENTIFIER".to_string(), "sshd".to_string()),
+                ("MESSAGE".to_string(), "Accepted password for alice from 10.0.0.5 port 1 ssh2".to_string()),
+            ])
+        };
+        assert!(parse_journal_entry(&entry("sshd", "ssh.service")).is_some());
+        assert!(parse_journal_entry(&entry("sshd-session", "sshd@3-10.0.0.1:22-10.0.0.5:1.service")).is_some());
+        // `logger -t sshd` from a login session
+        assert!(parse_journal_entry(&entry("logger", "session-4.scope")).is_none());
+        assert!(parse_journal_entry(&entry("sshd", "session-4.scope")).is_none());
+    }
+}
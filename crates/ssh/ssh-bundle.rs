@@ -0,0 +1,463 @@
+//! Signed grant bundles for hosts that sync with the team only occasionally.
+//!
+//! A bundle carries a host's rendered authorized_keys and the grants behind
+//! them, signed by an SSH admin. The admin's signing key is endorsed by the
+//! team owner, directly or through other admins, so a host pinned to the
+//! owner's key can verify a bundle without a daemon connection. Grant expiries
+//! are enforced by sshd through `expiry-time`; the bundle as a whole stops
+//! being enforced at its own expiry.
+//!
+//! Requests pushed to online host agents are signed the same way, so agents
+//! accept them only from SSH admins.
+
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use aranya_crypto::UserId;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::{
+    ssh_access::SshGrant,
+    ssh_deploy::{write_atomic_mode, AgentRequest},
+    ssh_keys::SshPublicKey,
+};
+
+/// Domain separator for signatures over endorsements.
+const ENDORSEMENT_CONTEXT: &[u8] = b"aranya-ssh-endorsement-v1";
+
+/// Domain separator for signatures over bundle contents.
+const BUNDLE_CONTEXT: &[u8] = b"aranya-ssh-grant-bundle-v1";
+
+/// Domain separator for signatures over agent requests.
+const REQUEST_CONTEXT: &[u8] = b"aranya-ssh-agent-request-v1";
+
+/// How far the signing time of an agent request may be from the agent's
+/// clock.
+pub const MAX_REQUEST_SKEW: Duration = Duration::from_secs(5 * 60);
+
+/// Longest a bundle is enforced after it is issued.
+pub const MAX_BUNDLE_VALIDITY: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Longest an endorsement lasts.
+pub const MAX_ENDORSEMENT_VALIDITY: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+/// An Ed25519 key signing bundles or endorsing other signers, held by a
+/// team device.
+pub struct BundleSigner {
+    device_id: UserId,
+    signing_key: SigningKey,
+    /// Signing time of the last agent request signed
+    last_signed_at: AtomicU64,
+}
+
+impl BundleSigner {
+    /// Load the signing key from `path`, generating it on first use.
+    pub async fn load_or_generate(device_id: UserId, path: &Path) -> Result<Self> {
+        let signing_key = match fs::read(path).await {
+            Ok(bytes) => {
+                let bytes: [u8; 32] = bytes
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("invalid bundle signing key in {}", path.display()))?;
+                SigningKey::from_bytes(&bytes)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let signing_key = SigningKey::generate(&mut OsRng);
+                write_atomic_mode(path, &signing_key.to_bytes(), 0o600)
+                    .await
+                    .with_context(|| format!("unable to write bundle signing key to {}", path.display()))?;
+                signing_key
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            device_id,
+            signing_key,
+            last_signed_at: AtomicU64::new(0),
+        })
+    }
+
+    /// The team device this key signs on behalf of.
+    pub fn device_id(&self) -> UserId {
+        self.device_id
+    }
+
+    /// The public key, as pinned on hosts for the team owner's signer.
+    pub fn public_key(&self) -> SshPublicKey {
+        SshPublicKey::from_ed25519(
+            self.signing_key.verifying_key().to_bytes(),
+            format!("aranya-ssh-bundle@{}", self.device_id),
+        )
+    }
+
+    /// Endorse another device's signing key until `expires_at`, or for
+    /// `MAX_ENDORSEMENT_VALIDITY` if that is sooner.
+    pub fn endorse(&self, device_id: UserId, key: &SshPublicKey, expires_at: SystemTime) -> Endorsement {
+        let latest = unix_secs(SystemTime::now()) + MAX_ENDORSEMENT_VALIDITY.as_secs();
+        let mut endorsement = Endorsement {
+            endorser: self.device_id.to_string(),
+            device_id: device_id.to_string(),
+            key: *key.ed25519(),
+            expires_at: unix_secs(expires_at).min(latest),
+            signature: Vec::new(),
+        };
+        endorsement.signature = self
+            .signing_key
+            .sign(&endorsement.signed_bytes())
+            .to_bytes()
+            .to_vec();
+        endorsement
+    }
+
+    /// Sign bundle contents. `chain` must lead from the team owner to this
+    /// key, and is empty when the owner signs directly.
+    pub fn sign(&self, contents: BundleContents, chain: Vec<Endorsement>) -> Result<GrantBundle> {
+        let signed = signed_bytes(BUNDLE_CONTEXT, &contents)?;
+        Ok(GrantBundle {
+            contents,
+            chain,
+            signature: self.signing_key.sign(&signed).to_bytes().to_vec(),
+        })
+    }
+
+    /// Sign a request to a host agent, with `chain` as for `sign`.
+    ///
+    /// Agents refuse a request not signed after the last one they installed,
+    /// so requests signed within the same second are given increasing
+    /// signing times.
+    pub fn sign_request(&self, request: AgentRequest, chain: Vec<Endorsement>) -> Result<SignedAgentRequest> {
+        let now = unix_secs(SystemTime::now());
+        let last = self
+            .last_signed_at
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| Some(now.max(last + 1)))
+            .unwrap_or_default();
+        let signed_at = now.max(last + 1);
+        let signed = signed_bytes(REQUEST_CONTEXT, &(&request, signed_at))?;
+        Ok(SignedAgentRequest {
+            request,
+            signed_at,
+            chain,
+            signature: self.signing_key.sign(&signed).to_bytes().to_vec(),
+        })
+    }
+}
+
+/// One link of a signer chain: `endorser` vouching for `device_id`'s key.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Endorsement {
+    pub endorser: String,
+    pub device_id: String,
+    /// The endorsed Ed25519 public key.
+    pub key: [u8; 32],
+    /// Seconds since the Unix epoch.
+    pub expires_at: u64,
+    pub signature: Vec<u8>,
+}
+
+impl Endorsement {
+    /// The bytes the endorser signs.
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut bytes = ENDORSEMENT_CONTEXT.to_vec();
+        for field in [&self.endorser, &self.device_id] {
+            bytes.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(field.as_bytes());
+        }
+        bytes.extend_from_slice(&self.key);
+        bytes.extend_from_slice(&self.expires_at.to_be_bytes());
+        bytes
+    }
+}
+
+/// What a bundle lets onto a host.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BundleContents {
+    pub hostname: String,
+    /// Seconds since the Unix epoch.
+    pub issued_at: u64,
+    /// Seconds since the Unix epoch, after which hosts stop enforcing the
+    /// bundle.
+    pub expires_at: u64,
+    /// Effective grants on the host, keyed by device id.
+    pub grants: BTreeMap<String, SshGrant>,
+    /// Keys accepted for any login.
+    pub authorized_keys: String,
+    /// Keys accepted for each mapped login.
+    pub user_keys: BTreeMap<String, String>,
+}
+
+/// Signed bundle contents and the signer chain back to the team owner.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GrantBundle {
+    pub contents: BundleContents,
+    pub chain: Vec<Endorsement>,
+    pub signature: Vec<u8>,
+}
+
+impl GrantBundle {
+    /// Encode the bundle for export.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        postcard::to_allocvec(self).context("unable to encode grant bundle")
+    }
+
+    /// Decode an exported bundle. The result is not verified.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        postcard::from_bytes(bytes).context("unable to decode grant bundle")
+    }
+
+    /// Check the bundle against the team owner's signing key at `now`.
+    ///
+    /// Every endorsement must be signed by the previous key in the chain,
+    /// starting from `owner`, and be unexpired. The bundle must be signed by
+    /// the last endorsed key and be unexpired itself. Bundles valid for
+    /// longer than `MAX_BUNDLE_VALIDITY` are rejected, so a leaked signer
+    /// key cannot mint one that outlives its revocation for long, as are
+    /// bundles issued more than `MAX_REQUEST_SKEW` after `now`, which would
+    /// otherwise block every bundle issued before them.
+    pub fn verify(&self, owner: &SshPublicKey, now: SystemTime) -> Result<&BundleContents> {
+        let now = unix_secs(now);
+        let signer = verify_chain(&self.chain, owner, now)?;
+
+        let signed = signed_bytes(BUNDLE_CONTEXT, &self.contents)?;
+        signer
+            .verify(&signed, &signature(&self.signature)?)
+            .context("grant bundle has a bad signature")?;
+        if self.contents.expires_at <= now {
+            bail!("grant bundle for {} has expired", self.contents.hostname);
+        }
+        ensure!(
+            self.contents.expires_at.saturating_sub(self.contents.issued_at) <= MAX_BUNDLE_VALIDITY.as_secs(),
+            "grant bundle for {} is valid for longer than {:?}",
+            self.contents.hostname,
+            MAX_BUNDLE_VALIDITY
+        );
+        ensure!(
+            self.contents.issued_at <= now + MAX_REQUEST_SKEW.as_secs(),
+            "grant bundle for {} is issued at {}, after {}",
+            self.contents.hostname,
+            self.contents.issued_at,
+            now
+        );
+
+        Ok(&self.contents)
+    }
+}
+
+/// An `AgentRequest` signed by an SSH admin, with the signer chain back to
+/// the team owner.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedAgentRequest {
+    pub request: AgentRequest,
+    /// Seconds since the Unix epoch.
+    pub signed_at: u64,
+    pub chain: Vec<Endorsement>,
+    pub signature: Vec<u8>,
+}
+
+impl SignedAgentRequest {
+    /// Encode the request for sending to a host agent.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        postcard::to_allocvec(self).context("unable to encode signed agent request")
+    }
+
+    /// Decode a request received from the manager. The result is not
+    /// verified.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        postcard::from_bytes(bytes).context("unable to decode signed agent request")
+    }
+
+    /// Check the request against the team owner's signing key at `now`.
+    ///
+    /// The chain is checked as for bundles, and the request must have been
+    /// signed within `MAX_REQUEST_SKEW` of `now`. Whether the endorsed
+    /// devices still hold `SSH_ADMIN_ROLE` is left to the caller.
+    pub fn verify(&self, owner: &SshPublicKey, now: SystemTime) -> Result<&AgentRequest> {
+        let now = unix_secs(now);
+        let signer = verify_chain(&self.chain, owner, now)?;
+
+        let signed = signed_bytes(REQUEST_CONTEXT, &(&self.request, self.signed_at))?;
+        signer
+            .verify(&signed, &signature(&self.signature)?)
+            .context("agent request has a bad signature")?;
+        ensure!(
+            self.signed_at.abs_diff(now) <= MAX_REQUEST_SKEW.as_secs(),
+            "agent request signed at {} is too far from {}",
+            self.signed_at,
+            now
+        );
+
+        Ok(&self.request)
+    }
+}
+
+/// Check a signer chain rooted at `owner`, returning the key it ends at.
+///
+/// Every endorsement must be signed by the previous key in the chain and be
+/// unexpired at `now`, expiring within `MAX_ENDORSEMENT_VALIDITY`.
+fn verify_chain(chain: &[Endorsement], owner: &SshPublicKey, now: u64) -> Result<VerifyingKey> {
+    let mut signer = verifying_key(owner.ed25519())?;
+    for (i, link) in chain.iter().enumerate() {
+        ensure!(
+            link.expires_at > now,
+            "endorsement {} of {} by {} has expired",
+            i,
+            link.device_id,
+            link.endorser
+        );
+        ensure!(
+            link.expires_at - now <= MAX_ENDORSEMENT_VALIDITY.as_secs(),
+            "endorsement {} of {} by {} lasts longer than {:?}",
+            i,
+            link.device_id,
+            link.endorser,
+            MAX_ENDORSEMENT_VALIDITY
+        );
+        signer
+            .verify(&link.signed_bytes(), &signature(&link.signature)?)
+            .with_context(|| format!("endorsement {} of {} has a bad signature", i, link.device_id))?;
+        signer = verifying_key(&link.key)?;
+    }
+    Ok(signer)
+}
+
+/// `value` encoded with postcard, after a domain separator.
+fn signed_bytes<T: Serialize>(context: &[u8], value: &T) -> Result<Vec<u8>> {
+    let mut bytes = context.to_vec();
+    bytes.extend(postcard::to_allocvec(value).context("unable to encode signed data")?);
+    Ok(bytes)
+}
+
+fn verifying_key(key: &[u8; 32]) -> Result<VerifyingKey> {
+    VerifyingKey::from_bytes(key).context("invalid Ed25519 public key")
+}
+
+fn signature(bytes: &[u8]) -> Result<Signature> {
+    Signature::from_slice(bytes).context("invalid Ed25519 signature")
+}
+
+/// Seconds since the Unix epoch, saturating at zero.
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh_deploy::HostArtifact;
+
+    fn signer() -> BundleSigner {
+        BundleSigner {
+            device_id: UserId::default(),
+            signing_key: SigningKey::generate(&mut OsRng),
+            last_signed_at: AtomicU64::new(0),
+        }
+    }
+
+    fn contents(now: SystemTime) -> BundleContents {
+        BundleContents {
+            hostname: "db1.example.com".to_string(),
+            issued_at: unix_secs(now),
+            expires_at: unix_secs(now + Duration::from_secs(3600)),
+            grants: BTreeMap::new(),
+            authorized_keys: String::new(),
+            user_keys: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn verifies_bundle_signed_through_chain() {
+        let (owner, admin) = (signer(), signer());
+        let now = SystemTime::now();
+        let link = owner.endorse(admin.device_id(), &admin.public_key(), now + Duration::from_secs(3600));
+        let bundle = admin.sign(contents(now), vec![link]).unwrap();
+
+        assert_eq!(bundle.verify(&owner.public_key(), now).unwrap(), &contents(now));
+    }
+
+    #[test]
+    fn rejects_bad_signature() {
+        let owner = signer();
+        let now = SystemTime::now();
+        let mut bundle = owner.sign(contents(now), Vec::new()).unwrap();
+        bundle.contents.authorized_keys.push_str("ssh-ed25519 AAAA intruder\n");
+
+        let err = bundle.verify(&owner.public_key(), now).unwrap_err();
+        assert!(err.to_string().contains("bad signature"), "{:#}", err);
+    }
+
+    #[test]
+    fn rejects_expired_endorsement() {
+        let (owner, admin) = (signer(), signer());
+        let now = SystemTime::now();
+        let link = owner.endorse(admin.device_id(), &admin.public_key(), now + Duration::from_secs(60));
+        let bundle = admin.sign(contents(now), vec![link]).unwrap();
+
+        let later = now + Duration::from_secs(120);
+        let err = bundle.verify(&owner.public_key(), later).unwrap_err();
+        assert!(err.to_string().contains("has expired"), "{:#}", err);
+    }
+
+    #[test]
+    fn rejects_wrong_owner() {
+        let (owner, other) = (signer(), signer());
+        let now = SystemTime::now();
+        let bundle = owner.sign(contents(now), Vec::new()).unwrap();
+
+        let err = bundle.verify(&other.public_key(), now).unwrap_err();
+        assert!(err.to_string().contains("bad signature"), "{:#}", err);
+    }
+
+    #[test]
+    fn rejects_overlong_bundle() {
+        let owner = signer();
+        let now = SystemTime::now();
+        let mut contents = contents(now);
+        contents.expires_at = unix_secs(now + MAX_BUNDLE_VALIDITY + Duration::from_secs(1));
+        let bundle = owner.sign(contents, Vec::new()).unwrap();
+
+        assert!(bundle.verify(&owner.public_key(), now).is_err());
+    }
+
+    #[test]
+    fn rejects_bundle_issued_in_the_future() {
+        let owner = signer();
+        let now = SystemTime::now();
+        let later = now + MAX_REQUEST_SKEW + Duration::from_secs(60);
+        let bundle = owner.sign(contents(later), Vec::new()).unwrap();
+
+        let err = bundle.verify(&owner.public_key(), now).unwrap_err();
+        assert!(err.to_string().contains("is issued at"), "{:#}", err);
+        assert!(bundle.verify(&owner.public_key(), later).is_ok());
+    }
+
+    #[test]
+    fn request_signing_times_increase() {
+        let owner = signer();
+        let request = || AgentRequest::Fetch {
+            hostname: "db1.example.com".to_string(),
+            artifact: HostArtifact::AuthorizedKeys,
+        };
+        let first = owner.sign_request(request(), Vec::new()).unwrap();
+        let second = owner.sign_request(request(), Vec::new()).unwrap();
+
+        assert!(second.signed_at > first.signed_at);
+        assert!(second.verify(&owner.public_key(), SystemTime::now()).is_ok());
+    }
+
+    #[test]
+    fn caps_endorsements() {
+        let (owner, admin) = (signer(), signer());
+        let now = SystemTime::now();
+        let link = owner.endorse(admin.device_id(), &admin.public_key(), now + 2 * MAX_ENDORSEMENT_VALIDITY);
+
+        assert!(link.expires_at <= unix_secs(now) + MAX_ENDORSEMENT_VALIDITY.as_secs() + 1);
+    }
+}
@@ -0,0 +1,378 @@
+//! OpenSSH user certificate authority.
+//!
+//! Certificates follow the `ssh-ed25519-cert-v01@openssh.com` format from
+//! OpenSSH's `PROTOCOL.certkeys`.
+
+use std::{
+    fmt,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{ensure, Context, Result};
+use aranya_crypto::UserId;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use ed25519_dalek::{Signer, SigningKey};
+use rand::{rngs::OsRng, RngCore};
+use tokio::{fs, io::AsyncWriteExt};
+
+use crate::ssh_keys::{put_string, SshPublicKey, SSH_ED25519};
+
+/// OpenSSH key type name for Ed25519 user certificates.
+pub const SSH_ED25519_CERT: &str = "ssh-ed25519-cert-v01@openssh.com";
+
+/// Principal added to certificates of devices holding `SSH_ADMIN_ROLE`.
+pub const SSH_ADMIN_PRINCIPAL: &str = "ssh-admin";
+
+/// Certificate type for user certificates.
+const SSH_CERT_TYPE_USER: u32 = 1;
+
+/// Allowance for clock skew between the CA and hosts.
+const CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+
+/// Extensions granted to every certificate, sorted by name as required by
+/// the certificate format.
+const DEFAULT_EXTENSIONS: &[&str] = &[
+    "permit-agent-forwarding",
+    "permit-port-forwarding",
+    "permit-pty",
+    "permit-user-rc",
+];
+
+/// How long certificates issued for each SSH role stay valid.
+#[derive(Clone, Debug)]
+pub struct CertificatePolicy {
+    /// Validity of certificates for devices with `SSH_ADMIN_ROLE`.
+    pub admin_validity: Duration,
+    /// Validity of certificates for devices with `SSH_USER_ROLE`.
+    pub user_validity: Duration,
+}
+
+impl Default for CertificatePolicy {
+    fn default() -> Self {
+        Self {
+            admin_validity: Duration::from_secs(60 * 60),
+            user_validity: Duration::from_secs(8 * 60 * 60),
+        }
+    }
+}
+
+/// The certificate authority held by the team device with `SSH_CA_ROLE`.
+pub struct SshCertificateAuthority {
+    device_id: UserId,
+    signing_key: SigningKey,
+}
+
+impl SshCertificateAuthority {
+    /// Load the CA signing key from `path`, generating it on first use.
+    pub async fn load_or_generate(device_id: UserId, path: &Path) -> Result<Self> {
+        let signing_key = match fs::read(path).await {
+            Ok(bytes) => {
+                let bytes: [u8; 32] = bytes
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("invalid CA key in {}", path.display()))?;
+                SigningKey::from_bytes(&bytes)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let signing_key = SigningKey::generate(&mut OsRng);
+                write_ca_key(path, &signing_key)
+                    .await
+                    .with_context(|| format!("unable to write CA key to {}", path.display()))?;
+                signing_key
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            device_id,
+            signing_key,
+        })
+    }
+
+    /// The team device this CA signs on behalf of.
+    pub fn device_id(&self) -> UserId {
+        self.device_id
+    }
+
+    /// The CA public key, as installed in `TrustedUserCAKeys` on hosts.
+    pub fn public_key(&self) -> SshPublicKey {
+        SshPublicKey::from_ed25519(
+            self.signing_key.verifying_key().to_bytes(),
+            format!("aranya-ssh-ca@{}", self.device_id),
+        )
+    }
+
+    /// Sign a user certificate for `key`.
+    pub fn sign(&self, key: &SshPublicKey, request: &CertificateRequest) -> Result<SshCertificate> {
+        ensure!(
+            !request.principals.is_empty(),
+            "certificate for {} has no principals",
+            request.key_id
+        );
+        ensure!(
+            request.valid_after < request.valid_before,
+            "certificate for {} has an empty validity window",
+            request.key_id
+        );
+
+        let mut nonce = [0u8; 32];
+        OsRng.fill_bytes(&mut nonce);
+
+        let mut buf = Vec::new();
+        put_string(&mut buf, SSH_ED25519_CERT.as_bytes());
+        put_string(&mut buf, &nonce);
+        put_string(&mut buf, key.ed25519());
+        buf.extend_from_slice(&request.serial.to_be_bytes());
+        buf.extend_from_slice(&SSH_CERT_TYPE_USER.to_be_bytes());
+        put_string(&mut buf, request.key_id.as_bytes());
+        put_string(&mut buf, &pack_strings(&request.principals));
+        buf.extend_from_slice(&unix_secs(request.valid_after).to_be_bytes());
+        buf.extend_from_slice(&unix_secs(request.valid_before).to_be_bytes());
+        put_string(&mut buf, &pack_options(&request.critical_options));
+        put_string(&mut buf, &pack_options(&request.extensions));
+        put_string(&mut buf, &[]);
+        put_string(&mut buf, &self.public_key().wire_format());
+
+        let signature = self.signing_key.sign(&buf);
+        let mut sig = Vec::new();
+        put_string(&mut sig, SSH_ED25519.as_bytes());
+        put_string(&mut sig, &signature.to_bytes());
+        put_string(&mut buf, &sig);
+
+        Ok(SshCertificate {
+            blob: buf,
+            comment: request.key_id.clone(),
+        })
+    }
+}
+
+/// The contents of a certificate to be signed.
+#[derive(Clone, Debug)]
+pub struct CertificateRequest {
+    /// Serial number, unique per CA.
+    pub serial: u64,
+    /// Key id logged by sshd, conventionally from `certificate_key_id`.
+    pub key_id: String,
+    /// Principals the certificate may log in as.
+    pub principals: Vec<String>,
+    /// Start of the validity window.
+    pub valid_after: SystemTime,
+    /// End of the validity window.
+    pub valid_before: SystemTime,
+    /// Critical options as (name, value) pairs, sorted by name.
+    pub critical_options: Vec<(String, String)>,
+    /// Extensions as (name, value) pairs, sorted by name.
+    pub extensions: Vec<(String, String)>,
+}
+
+impl CertificateRequest {
+    /// Create a request valid for `validity` from now, with the default
+    /// extensions and a random serial.
+    pub fn new(key_id: impl Into<String>, principals: Vec<String>, validity: Duration) -> Self {
+        let now = SystemTime::now();
+        Self {
+            serial: OsRng.next_u64(),
+            key_id: key_id.into(),
+            principals,
+            valid_after: now - CLOCK_SKEW,
+            valid_before: now + validity,
+            critical_options: Vec::new(),
+            extensions: DEFAULT_EXTENSIONS
+                .iter()
+                .map(|name| (name.to_string(), String::new()))
+                .collect(),
+        }
+    }
+}
+
+/// A signed OpenSSH user certificate.
+#[derive(Clone, Debug)]
+pub struct SshCertificate {
+    blob: Vec<u8>,
+    comment: String,
+}
+
+impl SshCertificate {
+    /// The encoded certificate.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.blob
+    }
+}
+
+/// Formats the certificate as the contents of an `id_ed25519-cert.pub` file.
+impl fmt::Display for SshCertificate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", SSH_ED25519_CERT, STANDARD.encode(&self.blob))?;
+        if !self.comment.is_empty() {
+            write!(f, " {}", self.comment)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write a new CA key, readable only by its owner.
+///
+/// The file is created with its final mode, so the key is never readable by
+/// others, and an existing file is never overwritten.
+async fn write_ca_key(path: &Path, signing_key: &SigningKey) -> Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .await?;
+    file.write_all(&signing_key.to_bytes()).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+/// Seconds since the Unix epoch, saturating at zero.
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Concatenate SSH `string`s, as used for the principals list.
+fn pack_strings(items: &[String]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for item in items {
+        put_string(&mut buf, item.as_bytes());
+    }
+    buf
+}
+
+/// Encode critical options or extensions. Non-empty values are themselves
+/// wrapped in a `string`.
+fn pack_options(options: &[(String, String)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (name, value) in options {
+        put_string(&mut buf, name.as_bytes());
+        if value.is_empty() {
+            put_string(&mut buf, &[]);
+        } else {
+            let mut inner = Vec::new();
+            put_string(&mut inner, value.as_bytes());
+            put_string(&mut buf, &inner);
+        }
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Signature, Verifier};
+
+    use super::*;
+    use crate::ssh_keys::get_string;
+
+    fn ca() -> SshCertificateAuthority {
+        SshCertificateAuthority {
+            device_id: UserId::default(),
+            signing_key: SigningKey::from_bytes(&[7; 32]),
+        }
+    }
+
+    fn request() -> CertificateRequest {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        CertificateRequest {
+            serial: 42,
+            valid_after: now,
+            valid_before: now + Duration::from_secs(3600),
+            ..CertificateRequest::new(
+                "device-1",
+                vec!["db1.example.com".into(), SSH_ADMIN_PRINCIPAL.into()],
+                Duration::from_secs(3600),
+            )
+        }
+    }
+
+    fn get_u64(buf: &mut &[u8]) -> u64 {
+        let (n, rest) = buf.split_at(8);
+        *buf = rest;
+        u64::from_be_bytes(n.try_into().unwrap())
+    }
+
+    #[test]
+    fn sign_encodes_certkeys_fields() {
+        let ca = ca();
+        let key = SshPublicKey::from_ed25519([1; 32], "device-1");
+        let cert = ca.sign(&key, &request()).unwrap();
+
+        let mut rest = cert.as_bytes();
+        assert_eq!(get_string(&mut rest).unwrap(), SSH_ED25519_CERT.as_bytes());
+        assert_eq!(get_string(&mut rest).unwrap().len(), 32);
+        assert_eq!(get_string(&mut rest).unwrap(), &[1; 32]);
+        assert_eq!(get_u64(&mut rest), 42);
+        let (cert_type, tail) = rest.split_at(4);
+        assert_eq!(cert_type, SSH_CERT_TYPE_USER.to_be_bytes());
+        rest = tail;
+        assert_eq!(get_string(&mut rest).unwrap(), b"device-1");
+
+        let mut principals = get_string(&mut rest).unwrap();
+        assert_eq!(get_string(&mut principals).unwrap(), b"db1.example.com");
+        assert_eq!(get_string(&mut principals).unwrap(), SSH_ADMIN_PRINCIPAL.as_bytes());
+        assert!(principals.is_empty());
+
+        assert_eq!(get_u64(&mut rest), 1_700_000_000);
+        assert_eq!(get_u64(&mut rest), 1_700_003_600);
+        assert!(get_string(&mut rest).unwrap().is_empty());
+        let mut extensions = get_string(&mut rest).unwrap();
+        for name in DEFAULT_EXTENSIONS {
+            assert_eq!(get_string(&mut extensions).unwrap(), name.as_bytes());
+            assert!(get_string(&mut extensions).unwrap().is_empty());
+        }
+        assert!(extensions.is_empty());
+        assert!(get_string(&mut rest).unwrap().is_empty());
+        assert_eq!(get_string(&mut rest).unwrap(), ca.public_key().wire_format());
+
+        // The signature covers everything before it
+        let signed = &cert.as_bytes()[..cert.as_bytes().len() - rest.len()];
+        let mut sig = get_string(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(get_string(&mut sig).unwrap(), SSH_ED25519.as_bytes());
+        let sig = Signature::from_slice(get_string(&mut sig).unwrap()).unwrap();
+        ca.signing_key.verifying_key().verify(signed, &sig).unwrap();
+    }
+
+    #[test]
+    fn sign_rejects_unusable_requests() {
+        let key = SshPublicKey::from_ed25519([1; 32], "device-1");
+        let no_principals = CertificateRequest {
+            principals: Vec::new(),
+            ..request()
+        };
+        assert!(ca().sign(&key, &no_principals).is_err());
+
+        let inverted = CertificateRequest {
+            valid_after: request().valid_before,
+            ..request()
+        };
+        assert!(ca().sign(&key, &inverted).is_err());
+    }
+
+    #[test]
+    fn option_values_are_wrapped_in_a_string() {
+        let options = vec![
+            ("force-command".to_string(), "uptime".to_string()),
+            ("no-touch-required".to_string(), String::new()),
+        ];
+        let mut rest = &pack_options(&options)[..];
+        assert_eq!(get_string(&mut rest).unwrap(), b"force-command");
+        let mut value = get_string(&mut rest).unwrap();
+        assert_eq!(get_string(&mut value).unwrap(), b"uptime");
+        assert!(value.is_empty());
+        assert_eq!(get_string(&mut rest).unwrap(), b"no-touch-required");
+        assert!(get_string(&mut rest).unwrap().is_empty());
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn display_is_a_cert_pub_line() {
+        let cert = ca()
+            .sign(&SshPublicKey::from_ed25519([1; 32], "device-1"), &request())
+            .unwrap()
+            .to_string();
+        assert!(cert.starts_with("ssh-ed25519-cert-v01@openssh.com AAAA"));
+        assert!(cert.ends_with(" device-1"));
+    }
+}
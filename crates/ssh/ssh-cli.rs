@@ -0,0 +1,317 @@
+//! `aranya-ssh`, the command-line front end of `SshAccessManager`.
+//!
+//! Talks to the SSH admin API of the local daemon hosting the manager, so it
+//! must run as the daemon's user or root. Every command prints a
+//! human-readable summary, or JSON with `--json`. Commands that only make a
+//! change print `{"ok": true}` as JSON.
+//!
+//! ```text
+//! aranya-ssh user add --keys alice.json
+//! aranya-ssh grant <DEVICE_ID> db1.example.com --level standard --expires-in 8h
+//! aranya-ssh who-can-access db1.example.com --json
+//! ```
+
+use std::{path::PathBuf, process::ExitCode, time::SystemTime};
+
+use anyhow::{bail, Context, Result};
+use aranya_daemon_api::KeyBundle;
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Serialize;
+
+use aranya_ssh::{
+    ssh_access::{SshAccessLevel, SshGrant},
+    ssh_api::{connect_ssh_api, AccessEntry},
+};
+
+#[derive(Debug, Parser)]
+#[clap(name = "aranya-ssh", author, version, about, long_about = None)]
+struct Args {
+    /// Path to the SSH admin API socket.
+    #[clap(long, default_value = "/var/run/aranya/ssh-admin.sock")]
+    ssh_uds_path: PathBuf,
+    /// Print JSON instead of human-readable output.
+    #[clap(long, global = true)]
+    json: bool,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Set up SSH access management for the team.
+    Init,
+    /// Manage SSH users.
+    User {
+        #[clap(subcommand)]
+        command: UserCommand,
+    },
+    /// Grant a member access to a host.
+    Grant {
+        /// Device id of the member.
+        member: String,
+        host: String,
+        #[clap(long, value_enum, default_value_t = Level::Standard)]
+        level: Level,
+        /// Forced command, required for `read-only`.
+        #[clap(long)]
+        command: Option<String>,
+        /// Source address or pattern to allow, may be repeated.
+        #[clap(long)]
+        from: Vec<String>,
+        /// Expire the grant after a duration such as `90m` or `8h`.
+        #[clap(long, value_parser = parse_duration)]
+        expires_in: Option<std::time::Duration>,
+    },
+    /// Revoke a member's access to a host.
+    Revoke {
+        /// Device id of the member.
+        member: String,
+        host: String,
+    },
+    /// Manage hosts.
+    Hosts {
+        #[clap(subcommand)]
+        command: HostsCommand,
+    },
+    /// List the members with access to a host.
+    WhoCanAccess { host: String },
+    /// List the hosts a member has access to.
+    WhatCan {
+        /// Device id of the member.
+        member: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum UserCommand {
+    /// Add a device to the team with an SSH role.
+    Add {
+        /// JSON file holding the device's public key bundle.
+        #[clap(long)]
+        keys: PathBuf,
+        /// Give the device `SSH_ADMIN_ROLE` instead of `SSH_USER_ROLE`.
+        #[clap(long)]
+        admin: bool,
+    },
+    /// Remove a device's SSH access and team membership.
+    Remove {
+        /// Device id of the member.
+        member: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum HostsCommand {
+    /// List enrolled hosts.
+    List,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Level {
+    ReadOnly,
+    Standard,
+    PortForwarding,
+    Admin,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    match run(&args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("aranya-ssh: {:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(args: &Args) -> Result<()> {
+    let api = connect_ssh_api(&args.ssh_uds_path).await?;
+    let ctx = tarpc::context::current;
+
+    match &args.command {
+        Command::Init => {
+            api.init(ctx()).await??;
+            done(args, || "initialized SSH access management".to_string())
+        }
+        Command::User {
+            command: UserCommand::Add { keys, admin },
+        } => {
+            let keys = std::fs::read(keys)
+                .with_context(|| format!("unable to read {}", keys.display()))?;
+            let keys: KeyBundle = serde_json::from_slice(&keys).context("invalid key bundle")?;
+            let device_id = api.add_user(ctx(), keys, *admin).await??;
+            print(args, &device_id, || format!("added {}", device_id))
+        }
+        Command::User {
+            command: UserCommand::Remove { member },
+        } => {
+            api.remove_user(ctx(), member.clone()).await??;
+            done(args, || format!("removed {}", member))
+        }
+        Command::Grant {
+            member,
+            host,
+            level,
+            command,
+            from,
+            expires_in,
+        } => {
+            let level = match (level, command) {
+                (Level::ReadOnly, Some(command)) => SshAccessLevel::ReadOnly {
+                    command: command.clone(),
+                },
+                (Level::ReadOnly, None) => bail!("read-only grants need --command"),
+                (_, Some(_)) => bail!("--command only applies to read-only grants"),
+                (Level::Standard, None) => SshAccessLevel::Standard,
+                (Level::PortForwarding, None) => SshAccessLevel::PortForwarding,
+                (Level::Admin, None) => SshAccessLevel::Admin,
+            };
+            let mut grant = SshGrant::new(level);
+            grant.from = from.clone();
+            if let Some(expires_in) = expires_in {
+                grant = grant.with_expiry(SystemTime::now() + *expires_in);
+            }
+            api.grant(ctx(), member.clone(), host.clone(), grant.clone())
+                .await??;
+            print(args, &grant, || {
+                format!("granted {} {} on {}", member, describe(&grant), host)
+            })
+        }
+        Command::Revoke { member, host } => {
+            api.revoke(ctx(), member.clone(), host.clone()).await??;
+            done(args, || format!("revoked {} from {}", host, member))
+        }
+        Command::Hosts {
+            command: HostsCommand::List,
+        } => {
+            let hosts = api.hosts(ctx()).await??;
+            print(args, &hosts, || {
+                let mut out = String::new();
+                for host in &hosts {
+                    let state = if host.approved { "approved" } else { "pending" };
+                    out.push_str(&format!(
+                        "{:<32} {:<9} {}\n",
+                        host.hostname,
+                        state,
+                        host.tags.join(",")
+                    ));
+                }
+                out.trim_end().to_string()
+            })
+        }
+        Command::WhoCanAccess { host } => {
+            let entries = api.who_can_access(ctx(), host.clone()).await??;
+            print(args, &entries, || {
+                access_table(&entries, |entry| entry.device_id.clone())
+            })
+        }
+        Command::WhatCan { member } => {
+            let entries = api.what_can(ctx(), member.clone()).await??;
+            print(args, &entries, || {
+                access_table(&entries, |entry| entry.hostname.clone())
+            })
+        }
+    }
+}
+
+/// Print `value` as JSON, or the human-readable rendering of it.
+fn print<T: Serialize>(args: &Args, value: &T, human: impl FnOnce() -> String) -> Result<()> {
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(value)?);
+    } else {
+        let human = human();
+        if !human.is_empty() {
+            println!("{}", human);
+        }
+    }
+    Ok(())
+}
+
+/// Report a change that returns nothing, as `{"ok": true}` in JSON.
+fn done(args: &Args, human: impl FnOnce() -> String) -> Result<()> {
+    print(args, &serde_json::json!({ "ok": true }), human)
+}
+
+/// One line per entry: its `key`, level, logins and expiry.
+fn access_table(entries: &[AccessEntry], key: impl Fn(&AccessEntry) -> String) -> String {
+    let mut out = String::new();
+    for entry in entries {
+        let logins = match &entry.logins {
+            Some(logins) => logins.join(","),
+            None => "*".to_string(),
+        };
+        out.push_str(&format!(
+            "{:<44} {:<40} {}\n",
+            key(entry),
+            describe(&entry.grant),
+            logins
+        ));
+    }
+    out.trim_end().to_string()
+}
+
+/// A short description of a grant's level, sources and expiry.
+fn describe(grant: &SshGrant) -> String {
+    let mut out = match &grant.level {
+        SshAccessLevel::ReadOnly { command } => format!("read-only ({})", command),
+        SshAccessLevel::Standard => "standard".to_string(),
+        SshAccessLevel::PortForwarding => "port-forwarding".to_string(),
+        SshAccessLevel::Admin => "admin".to_string(),
+    };
+    if !grant.from.is_empty() {
+        out.push_str(&format!(" from {}", grant.from.join(",")));
+    }
+    if let Some(expires_at) = grant.expires_at {
+        let expires_at = chrono::DateTime::<chrono::Utc>::from(expires_at);
+        out.push_str(&format!(" until {}", expires_at.format("%Y-%m-%d %H:%M UTC")));
+    }
+    out
+}
+
+/// Parse a duration such as `30s`, `90m`, `8h` or `2d`.
+fn parse_duration(s: &str) -> Result<std::time::Duration> {
+    let (n, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let n: u64 = n.parse().with_context(|| format!("invalid duration `{}`", s))?;
+    let unit_secs = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!("unknown duration unit in `{}`", s),
+    };
+    let secs = n
+        .checked_mul(unit_secs)
+        .with_context(|| format!("duration `{}` is too long", s))?;
+    Ok(std::time::Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_take_a_unit_suffix() {
+        assert_eq!(parse_duration("45").unwrap().as_secs(), 45);
+        assert_eq!(parse_duration("90m").unwrap().as_secs(), 90 * 60);
+        assert_eq!(parse_duration("8h").unwrap().as_secs(), 8 * 60 * 60);
+        assert_eq!(parse_duration("2d").unwrap().as_secs(), 2 * 24 * 60 * 60);
+        assert!(parse_duration("8w").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration(&format!("{}d", u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn grants_are_described_by_level_sources_and_expiry() {
+        let mut grant = SshGrant::new(SshAccessLevel::ReadOnly {
+            command: "uptime".to_string(),
+        });
+        grant.from = vec!["10.0.0.0/8".to_string()];
+        assert_eq!(describe(&grant), "read-only (uptime) from 10.0.0.0/8");
+
+        let grant = SshGrant::new(SshAccessLevel::Standard)
+            .with_expiry(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(86_400));
+        assert_eq!(describe(&grant), "standard until 1970-01-02 00:00 UTC");
+    }
+}
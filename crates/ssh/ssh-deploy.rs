@@ -0,0 +1,353 @@
+//! Backends delivering rendered SSH files to hosts.
+//!
+//! The manager renders each host's files locally and hands them to a
+//! `DeployBackend`. `LocalFsDeployer` writes them to a directory, for hosts
+//! sharing a filesystem with the manager and for testing. `AfcDeployer` pushes
+//! them over Aranya Fast Channels to the host agent running on each host,
+//! signing each request so the agent can tell it comes from an SSH admin.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::Permissions,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use aranya_client::{AfcId, Client};
+use aranya_daemon_api::{NetIdentifier, TeamId};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex, time};
+
+use crate::{
+    ssh_access::validate_hostname,
+    ssh_aranya::SSH_LABEL,
+    ssh_bundle::{BundleSigner, Endorsement, SignedAgentRequest},
+};
+
+/// How long `AfcDeployer` waits for an agent to answer a fetch.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A file the manager maintains on each host.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum HostArtifact {
+    /// `AuthorizedKeysFile` contents shared by every login.
+    AuthorizedKeys,
+    /// `AuthorizedKeysFile` contents for one login.
+    UserKeys(String),
+    /// CA key for `TrustedUserCAKeys`.
+    CaTrust,
+    /// `AuthorizedPrincipalsFile` contents.
+    Principals,
+    /// `/etc/sudoers.d` fragment.
+    Sudoers,
+    /// KRL for `RevokedKeys`.
+    Krl,
+}
+
+impl HostArtifact {
+    /// Path of the artifact's file on the host, relative to the install
+    /// directory.
+    pub fn relative_path(&self) -> PathBuf {
+        match self {
+            Self::AuthorizedKeys => PathBuf::from("authorized_keys"),
+            Self::UserKeys(login) => Path::new("users").join(login),
+            Self::CaTrust => PathBuf::from("ca.pub"),
+            Self::Principals => PathBuf::from("principals"),
+            Self::Sudoers => PathBuf::from("sudoers"),
+            Self::Krl => PathBuf::from("revoked_keys"),
+        }
+    }
+}
+
+impl fmt::Display for HostArtifact {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.relative_path().display())
+    }
+}
+
+/// New contents of one artifact on one host.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostUpdate {
+    pub hostname: String,
+    pub artifact: HostArtifact,
+    pub contents: Vec<u8>,
+}
+
+/// A message from the manager to a host agent, sent as a
+/// `SignedAgentRequest`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AgentRequest {
+    /// Install an update.
+    Install(HostUpdate),
+    /// Send back the installed contents of an artifact.
+    Fetch {
+        hostname: String,
+        artifact: HostArtifact,
+    },
+}
+
+/// A host agent's answer to `AgentRequest::Fetch`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AgentResponse {
+    pub hostname: String,
+    pub artifact: HostArtifact,
+    /// The installed contents, `None` if the file does not exist.
+    pub contents: Option<Vec<u8>>,
+}
+
+impl AgentResponse {
+    /// Encode the response for sending to the manager.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        postcard::to_allocvec(self).context("unable to encode agent response")
+    }
+
+    /// Decode a response received from a host agent.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        postcard::from_bytes(bytes).context("unable to decode agent response")
+    }
+}
+
+/// How far a backend got delivering an update.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeployOutcome {
+    /// The file is in place on the host.
+    Installed,
+    /// The update reached the host's agent, which installs it.
+    Sent,
+}
+
+/// The last delivery attempt of an artifact to a host.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeployStatus {
+    pub artifact: HostArtifact,
+    pub attempted_at: SystemTime,
+    /// The outcome, or the error that stopped delivery.
+    pub result: Result<DeployOutcome, String>,
+}
+
+impl DeployStatus {
+    /// Reports whether the update was delivered.
+    pub fn is_ok(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+/// Delivers rendered files to hosts.
+#[async_trait]
+pub trait DeployBackend: Send + Sync {
+    /// Short name of the backend for logs.
+    fn name(&self) -> &'static str;
+
+    /// Deliver an update to its host.
+    async fn deploy(&self, update: &HostUpdate) -> Result<DeployOutcome>;
+
+    /// Read an artifact as installed on a host, `None` if it is missing.
+    async fn fetch(&self, hostname: &str, artifact: &HostArtifact) -> Result<Option<Vec<u8>>>;
+}
+
+/// Writes each host's files to `<root>/<hostname>/<file>`.
+#[derive(Clone, Debug)]
+pub struct LocalFsDeployer {
+    root: PathBuf,
+}
+
+impl LocalFsDeployer {
+    /// Deploy under `root`.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Where an artifact is written for a host. `hostname` must pass
+    /// `validate_hostname`, which `deploy` and `fetch` check.
+    pub fn path(&self, hostname: &str, artifact: &HostArtifact) -> PathBuf {
+        self.root.join(hostname).join(artifact.relative_path())
+    }
+}
+
+#[async_trait]
+impl DeployBackend for LocalFsDeployer {
+    fn name(&self) -> &'static str {
+        "local-fs"
+    }
+
+    async fn deploy(&self, update: &HostUpdate) -> Result<DeployOutcome> {
+        validate_hostname(&update.hostname)?;
+        write_atomic(&self.path(&update.hostname, &update.artifact), &update.contents).await?;
+        Ok(DeployOutcome::Installed)
+    }
+
+    async fn fetch(&self, hostname: &str, artifact: &HostArtifact) -> Result<Option<Vec<u8>>> {
+        validate_hostname(hostname)?;
+        match fs::read(self.path(hostname, artifact)).await {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Pushes updates to host agents over AFC channels on `SSH_LABEL`.
+///
+/// Hosts must be added with the AFC address their agent listens on. One
+/// channel is opened per host and reused for later updates.
+///
+/// Requests are signed by `signer`, whose chain must lead from the team
+/// owner through devices holding `SSH_ADMIN_ROLE`, as agents check.
+pub struct AfcDeployer {
+    client: Mutex<Client>,
+    team_id: TeamId,
+    signer: BundleSigner,
+    chain: Vec<Endorsement>,
+    hosts: Mutex<BTreeMap<String, NetIdentifier>>,
+    channels: Mutex<BTreeMap<String, AfcId>>,
+}
+
+impl AfcDeployer {
+    /// Push updates for `team_id` through `client`, signed by `signer`.
+    /// `chain` is empty when `signer` is the team owner's.
+    pub fn new(client: Client, team_id: TeamId, signer: BundleSigner, chain: Vec<Endorsement>) -> Self {
+        Self {
+            client: Mutex::new(client),
+            team_id,
+            signer,
+            chain,
+            hosts: Mutex::new(BTreeMap::new()),
+            channels: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Set the AFC address of a host's agent.
+    pub async fn add_host(&self, hostname: impl Into<String>, addr: NetIdentifier) {
+        let hostname = hostname.into();
+        // A new address needs a new channel
+        self.channels.lock().await.remove(&hostname);
+        self.hosts.lock().await.insert(hostname, addr);
+    }
+
+    /// Stop pushing updates to a host.
+    pub async fn remove_host(&self, hostname: &str) {
+        self.channels.lock().await.remove(hostname);
+        self.hosts.lock().await.remove(hostname);
+    }
+
+    /// The channel to a host's agent, opening it if needed.
+    async fn channel(&self, client: &mut Client, hostname: &str) -> Result<AfcId> {
+        let mut channels = self.channels.lock().await;
+        if let Some(id) = channels.get(hostname) {
+            return Ok(*id);
+        }
+        let addr = self
+            .hosts
+            .lock()
+            .await
+            .get(hostname)
+            .cloned()
+            .with_context(|| format!("no AFC address for host {}", hostname))?;
+        let id = client
+            .create_afc_bidi_channel(self.team_id, addr, SSH_LABEL)
+            .await
+            .with_context(|| format!("unable to open AFC channel to {}", hostname))?;
+        channels.insert(hostname.to_string(), id);
+        Ok(id)
+    }
+
+    /// Sign a request and send it to a host's agent.
+    async fn send(&self, client: &mut Client, hostname: &str, request: AgentRequest) -> Result<()> {
+        let msg = self.signer.sign_request(request, self.chain.clone())?.to_bytes()?;
+        let id = self.channel(client, hostname).await?;
+        if let Err(err) = client.send_afc_data(id, &msg).await {
+            // The agent may have restarted, so reopen the channel next time
+            self.channels.lock().await.remove(hostname);
+            return Err(err).with_context(|| format!("unable to send to {}", hostname));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DeployBackend for AfcDeployer {
+    fn name(&self) -> &'static str {
+        "afc"
+    }
+
+    async fn deploy(&self, update: &HostUpdate) -> Result<DeployOutcome> {
+        let mut client = self.client.lock().await;
+        self.send(&mut client, &update.hostname, AgentRequest::Install(update.clone()))
+            .await?;
+        Ok(DeployOutcome::Sent)
+    }
+
+    async fn fetch(&self, hostname: &str, artifact: &HostArtifact) -> Result<Option<Vec<u8>>> {
+        let request = AgentRequest::Fetch {
+            hostname: hostname.to_string(),
+            artifact: artifact.clone(),
+        };
+        let mut client = self.client.lock().await;
+        self.send(&mut client, hostname, request).await?;
+
+        let recv = async {
+            loop {
+                while let Some(msg) = client.try_recv_afc_data() {
+                    // Anything other than the answer is stale and dropped
+                    let Ok(response) = AgentResponse::from_bytes(&msg.data) else {
+                        continue;
+                    };
+                    if response.hostname == hostname && response.artifact == *artifact {
+                        return Ok(response.contents);
+                    }
+                }
+                let data = client.poll_afc_data().await?;
+                client.handle_afc_data(data).await?;
+            }
+        };
+        time::timeout(FETCH_TIMEOUT, recv)
+            .await
+            .with_context(|| format!("{} did not answer within {:?}", hostname, FETCH_TIMEOUT))?
+    }
+}
+
+/// Replace `path` with `contents` so readers see either the old or the new
+/// file, never a partial one.
+///
+/// Writes a temporary file in the same directory, syncs it and renames it
+/// over `path`.
+pub async fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    write_atomic_mode(path, contents, 0o644).await
+}
+
+/// Like `write_atomic`, with the new file's permissions set to `mode`
+/// before it replaces `path`.
+pub async fn write_atomic_mode(path: &Path, contents: &[u8], mode: u32) -> Result<()> {
+    let dir = path
+        .parent()
+        .with_context(|| format!("{} has no parent directory", path.display()))?;
+    fs::create_dir_all(dir).await?;
+
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("{} has no file name", path.display()))?;
+    let mut tmp_name = file_name.to_os_string();
+    tmp_name.push(".tmp");
+    let tmp = dir.join(tmp_name);
+
+    let mut file = fs::File::create(&tmp)
+        .await
+        .with_context(|| format!("unable to create {}", tmp.display()))?;
+    file.write_all(contents).await?;
+    // Set explicitly, the umask applies at creation
+    file.set_permissions(Permissions::from_mode(mode)).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("unable to replace {}", path.display()))?;
+    // Persist the rename itself
+    fs::File::open(dir).await?.sync_all().await?;
+
+    Ok(())
+}
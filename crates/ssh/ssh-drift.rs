@@ -0,0 +1,284 @@
+//! Drift between the files installed on hosts and the team graph.
+//!
+//! `SshAccessManager::audit_drift` compares the authorized_keys files and
+//! sudoers fragment each host actually has with what the team graph says it
+//! should have. Reports serialize to JSON for monitoring.
+
+use std::collections::{btree_map::Entry, BTreeMap, BTreeSet};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{ssh_deploy::HostArtifact, ssh_keys::SshPublicKey};
+
+/// Where installed keys are read from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DriftSource {
+    /// The manager's rendered copies in `keys_path`.
+    KeysPath,
+    /// The hosts themselves, through the deployment backend.
+    Deployed,
+}
+
+/// An `authorized_keys` entry, or a sudoers line.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DriftEntry {
+    /// SHA256 fingerprint of the entry's key, if it parses.
+    pub fingerprint: Option<String>,
+    /// The entry's key comment, the device id for rendered entries.
+    pub comment: Option<String>,
+    pub line: String,
+}
+
+/// An entry whose key is expected but whose line differs, e.g. hand-edited
+/// options.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModifiedEntry {
+    pub fingerprint: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// How one installed file differs from the team graph.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileDrift {
+    /// The host does not have the file at all.
+    pub file_missing: bool,
+    /// Entries the graph does not grant.
+    pub extra: Vec<DriftEntry>,
+    /// Entries the graph grants that are not installed.
+    pub missing: Vec<DriftEntry>,
+    pub modified: Vec<ModifiedEntry>,
+    /// Entries repeating the key of an earlier installed entry. sshd uses
+    /// the first line matching a key, so a later one with other options is
+    /// dead weight at best and misleading at worst.
+    pub duplicate: Vec<DriftEntry>,
+}
+
+impl FileDrift {
+    /// Compare installed keys with the expected keys. Comment lines are
+    /// ignored.
+    pub fn compare(actual: Option<&str>, expected: &str) -> Self {
+        let mut drift = Self {
+            file_missing: actual.is_none(),
+            ..Self::default()
+        };
+        let (actual, unparsed, duplicate) = entries(actual.unwrap_or_default());
+        let (mut expected, _, _) = entries(expected);
+
+        // Lines that do not parse are never expected
+        drift.extra = unparsed;
+        drift.duplicate = duplicate;
+        for (fingerprint, entry) in actual {
+            match expected.remove(&fingerprint) {
+                Some(want) if want.line == entry.line => {}
+                Some(want) => drift.modified.push(ModifiedEntry {
+                    fingerprint,
+                    expected: want.line,
+                    actual: entry.line,
+                }),
+                None => drift.extra.push(entry),
+            }
+        }
+        drift.missing = expected.into_values().collect();
+
+        drift
+    }
+
+    /// Compare a file line by line, for files other than authorized_keys
+    /// such as sudoers fragments. Comment lines are ignored and nothing is
+    /// reported as modified.
+    pub fn compare_lines(actual: Option<&str>, expected: &str) -> Self {
+        let lines = |contents: &str| -> BTreeSet<String> {
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(String::from)
+                .collect()
+        };
+        let entry = |line: &String| DriftEntry {
+            fingerprint: None,
+            comment: None,
+            line: line.clone(),
+        };
+        let (installed, wanted) = (lines(actual.unwrap_or_default()), lines(expected));
+
+        Self {
+            file_missing: actual.is_none(),
+            extra: installed.difference(&wanted).map(entry).collect(),
+            missing: wanted.difference(&installed).map(entry).collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Reports whether the installed file matches the graph.
+    pub fn is_clean(&self) -> bool {
+        !self.file_missing
+            && self.extra.is_empty()
+            && self.missing.is_empty()
+            && self.modified.is_empty()
+            && self.duplicate.is_empty()
+    }
+}
+
+/// How one host's installed files differ from the team graph.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostDrift {
+    /// Drifted files, by path relative to the install directory. Files
+    /// without drift are left out.
+    pub files: BTreeMap<String, FileDrift>,
+    /// Set once the host's files have been rewritten from the graph.
+    pub remediated: bool,
+}
+
+impl HostDrift {
+    /// Record a file's drift, if it has any.
+    pub fn add(&mut self, artifact: &HostArtifact, drift: FileDrift) {
+        if !drift.is_clean() {
+            self.files.insert(artifact.to_string(), drift);
+        }
+    }
+
+    /// Reports whether every installed file matches the graph.
+    pub fn is_clean(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+/// Drift across hosts. Hosts without drift are left out.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DriftReport {
+    pub source: DriftSource,
+    pub hosts: BTreeMap<String, HostDrift>,
+    /// Hosts that could not be audited, with the error that stopped them.
+    pub errors: BTreeMap<String, String>,
+}
+
+impl DriftReport {
+    /// Reports whether every host was audited and matches the graph.
+    pub fn is_clean(&self) -> bool {
+        self.hosts.is_empty() && self.errors.is_empty()
+    }
+
+    /// The report as JSON.
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).context("unable to encode drift report")
+    }
+}
+
+/// Parse `authorized_keys` contents into entries keyed by fingerprint, the
+/// lines that do not parse, and the entries repeating an earlier key.
+fn entries(contents: &str) -> (BTreeMap<String, DriftEntry>, Vec<DriftEntry>, Vec<DriftEntry>) {
+    let mut entries = BTreeMap::new();
+    let mut unparsed = Vec::new();
+    let mut duplicate = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.parse::<SshPublicKey>() {
+            Ok(key) => {
+                let fingerprint = key.fingerprint();
+                let entry = DriftEntry {
+                    fingerprint: Some(fingerprint.clone()),
+                    comment: Some(key.comment().to_string()).filter(|c| !c.is_empty()),
+                    line: line.to_string(),
+                };
+                // The first line for a key is the one sshd uses
+                match entries.entry(fingerprint) {
+                    Entry::Occupied(_) => duplicate.push(entry),
+                    Entry::Vacant(slot) => {
+                        slot.insert(entry);
+                    }
+                }
+            }
+            Err(_) => unparsed.push(DriftEntry {
+                fingerprint: None,
+                comment: None,
+                line: line.to_string(),
+            }),
+        }
+    }
+    (entries, unparsed, duplicate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f alice";
+    const BOB: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIJlBEpoc5Ej7EFqINpQMGVHMzVX/XlSLf09p21MF+w3i bob";
+
+    fn lines(entries: &[DriftEntry]) -> Vec<&str> {
+        entries.iter().map(|e| e.line.as_str()).collect()
+    }
+
+    #[test]
+    fn matching_keys_are_clean() {
+        let expected = format!("# Generated\nrestrict,pty {}\n{}\n", ALICE, BOB);
+        let actual = format!("restrict,pty {}\n\n# edited by hand\n{}\n", ALICE, BOB);
+        assert!(FileDrift::compare(Some(&actual), &expected).is_clean());
+
+        let drift = FileDrift::compare(None, "# Generated\n");
+        assert!(drift.file_missing);
+        assert!(!drift.is_clean());
+    }
+
+    #[test]
+    fn compare_sorts_entries_by_how_they_differ() {
+        let expected = format!("restrict,pty {}\n", ALICE);
+        let actual = format!("{}\n{}\nnot a key\n", ALICE, BOB);
+        let drift = FileDrift::compare(Some(&actual), &expected);
+
+        assert_eq!(lines(&drift.extra), ["not a key", BOB]);
+        assert!(drift.missing.is_empty());
+        assert_eq!(drift.modified.len(), 1);
+        assert_eq!(drift.modified[0].expected, format!("restrict,pty {}", ALICE));
+        assert_eq!(drift.modified[0].actual, ALICE);
+
+        let drift = FileDrift::compare(Some(""), &format!("{}\n", BOB));
+        assert_eq!(lines(&drift.missing), [BOB]);
+        assert_eq!(drift.missing[0].comment.as_deref(), Some("bob"));
+    }
+
+    #[test]
+    fn repeated_keys_are_duplicates() {
+        let expected = format!("restrict,pty {}\n", ALICE);
+        let actual = format!("restrict,pty {}\n{}\n", ALICE, ALICE);
+        let drift = FileDrift::compare(Some(&actual), &expected);
+
+        assert_eq!(lines(&drift.duplicate), [ALICE]);
+        assert!(drift.modified.is_empty());
+        assert!(!drift.is_clean());
+    }
+
+    #[test]
+    fn sudoers_lines_compare_as_text() {
+        let expected = "# Generated\n# device (ssh-admin)\nops ALL=(ALL:ALL) NOPASSWD: ALL\n";
+        let actual = "ops ALL=(ALL:ALL) NOPASSWD: ALL\nDefaults !requiretty\n";
+        let drift = FileDrift::compare_lines(Some(actual), expected);
+        assert_eq!(lines(&drift.extra), ["Defaults !requiretty"]);
+        assert!(drift.missing.is_empty());
+
+        let drift = FileDrift::compare_lines(None, expected);
+        assert!(drift.file_missing);
+        assert_eq!(lines(&drift.missing), ["ops ALL=(ALL:ALL) NOPASSWD: ALL"]);
+    }
+
+    #[test]
+    fn host_drift_only_records_drifted_files() {
+        let keys = format!("{}\n", ALICE);
+        let mut drift = HostDrift::default();
+        drift.add(&HostArtifact::AuthorizedKeys, FileDrift::compare(Some(&keys), &keys));
+        assert!(drift.is_clean());
+
+        let login = HostArtifact::UserKeys("deploy".to_string());
+        drift.add(&login, FileDrift::compare(None, &keys));
+        assert_eq!(drift.files.keys().collect::<Vec<_>>(), [&login.to_string()]);
+        assert!(!drift.is_clean());
+    }
+}
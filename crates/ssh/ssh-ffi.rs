@@ -0,0 +1,50 @@
+//! Policy FFI for the checks the SSH policy cannot express itself.
+//!
+//! The policy language has no string operations, so validation the policy
+//! has to enforce on every device is exported from here as the `ssh`
+//! module. Daemons running `ssh-policy.md` must include `SshFfi` in their
+//! policy engine's FFI modules.
+
+use aranya_crypto::Engine;
+use aranya_policy_vm::{ffi::ffi, CommandContext, MachineError};
+
+use crate::ssh_access::{validate_hostname, SshAccessLevel, SshGrant};
+
+/// The `ssh` policy FFI module.
+pub struct SshFfi;
+
+#[ffi(module = "ssh")]
+impl SshFfi {
+    /// Reports whether a grant's graph values are valid, as checked by
+    /// `SshGrant::validate`.
+    #[ffi_export(def = "function valid_grant(level int, command string, from string) bool")]
+    pub(crate) fn valid_grant<E: Engine>(
+        &self,
+        _ctx: &CommandContext<'_>,
+        _eng: &mut E,
+        level: i64,
+        command: String,
+        from: String,
+    ) -> Result<bool, MachineError> {
+        let Ok(level) = SshAccessLevel::from_policy(level, command) else {
+            return Ok(false);
+        };
+        let mut grant = SshGrant::new(level);
+        if !from.is_empty() {
+            grant.from = from.split(',').map(String::from).collect();
+        }
+        Ok(grant.validate().is_ok())
+    }
+
+    /// Reports whether `hostname` is a valid hostname, as checked by
+    /// `validate_hostname`.
+    #[ffi_export(def = "function valid_hostname(hostname string) bool")]
+    pub(crate) fn valid_hostname<E: Engine>(
+        &self,
+        _ctx: &CommandContext<'_>,
+        _eng: &mut E,
+        hostname: String,
+    ) -> Result<bool, MachineError> {
+        Ok(validate_hostname(&hostname).is_ok())
+    }
+}
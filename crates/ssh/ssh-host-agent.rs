@@ -0,0 +1,473 @@
+//! Host agent installing SSH files pushed by the access manager.
+//!
+//! The agent runs next to an Aranya daemon on each SSH host, with the host
+//! enrolled in the team as a device holding `SSH_LABEL`. It accepts
+//! `AgentRequest`s over AFC channels on `SSH_LABEL`, installing updates
+//! atomically and answering fetches of installed files for drift audits.
+//! The label alone is not enough since SSH users hold it too, so requests
+//! must be signed by a chain of endorsements from the team owner's key in
+//! `--owner-key`, and every endorsed device must hold `SSH_ADMIN_ROLE`.
+//! Each artifact's last install is recorded in the install directory by
+//! signing time, and requests not signed after it are rejected, so a
+//! captured update cannot be replayed, even across agent restarts.
+//!
+//! sudoers fragments are installed to `--sudoers-file` rather than the
+//! install directory, and only once `visudo -cf` accepts them.
+//!
+//! With `--bundle-file`, the agent does not connect to a daemon and instead
+//! enforces a signed grant bundle exported by the manager, verified against
+//! the same owner key. The keys are emptied once the bundle expires or stops
+//! verifying. The issue time of the newest bundle installed is kept in the
+//! install directory, and older bundles are refused so a stale bundle cannot
+//! be put back to restore revoked access.
+//!
+//! Example `sshd_config`:
+//!
+//! ```text
+//! AuthorizedKeysFile /etc/ssh/aranya/authorized_keys /etc/ssh/aranya/users/%u
+//! TrustedUserCAKeys /etc/ssh/aranya/ca.pub
+//! AuthorizedPrincipalsFile /etc/ssh/aranya/principals
+//! RevokedKeys /etc/ssh/aranya/revoked_keys
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use aranya_client::{AfcMsg, Client};
+use aranya_daemon_api::TeamId;
+use clap::Parser;
+use tokio::{fs, time};
+use tracing::{info, warn};
+
+use aranya_ssh::{
+    ssh_access::validate_login,
+    ssh_aranya::{SSH_ADMIN_ROLE, SSH_LABEL},
+    ssh_bundle::{BundleContents, GrantBundle, SignedAgentRequest},
+    ssh_deploy::{
+        write_atomic, write_atomic_mode, AgentRequest, AgentResponse, HostArtifact, HostUpdate,
+    },
+    ssh_keys::SshPublicKey,
+    ssh_krl::krl_version,
+    ssh_sudoers::{check_sudoers, sudoers_logins},
+};
+
+#[derive(Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// Path to the daemon's UDS API socket.
+    #[clap(long, default_value = "/var/run/aranya/uds.sock")]
+    uds_path: PathBuf,
+    /// Path to the daemon's AFC shared memory.
+    #[clap(long, default_value = "/afc")]
+    shm_path: String,
+    /// Maximum number of AFC channels.
+    #[clap(long, default_value_t = 100)]
+    max_chans: usize,
+    /// Team to accept updates from.
+    #[clap(long)]
+    team: TeamId,
+    /// Hostname updates must be addressed to, defaults to the local hostname.
+    #[clap(long)]
+    host: Option<String>,
+    /// Directory the files are installed in.
+    #[clap(long, default_value = "/etc/ssh/aranya")]
+    install_dir: PathBuf,
+    /// Where the sudoers fragment is installed. The name must not contain a
+    /// `.`, or sudo skips the file.
+    #[clap(long, default_value = "/etc/sudoers.d/aranya-ssh")]
+    sudoers_file: PathBuf,
+    /// Enforce this signed grant bundle instead of accepting updates.
+    #[clap(long)]
+    bundle_file: Option<PathBuf>,
+    /// File holding the team owner's bundle signing key, which requests and
+    /// bundles must be signed under.
+    #[clap(long)]
+    owner_key: PathBuf,
+}
+
+/// File in the install directory recording the signing time of the last
+/// update installed for each artifact.
+const LAST_SIGNED_FILE: &str = "last_signed";
+
+/// File in the install directory recording the issue time of the newest
+/// grant bundle installed.
+const BUNDLE_ISSUED_AT_FILE: &str = "bundle_issued_at";
+
+/// How often the grant bundle is re-read and checked for expiry.
+const BUNDLE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+    let args = Args::parse();
+
+    let hostname = match &args.host {
+        Some(host) => host.clone(),
+        None => local_hostname()?,
+    };
+
+    let owner = fs::read_to_string(&args.owner_key)
+        .await
+        .with_context(|| format!("unable to read {}", args.owner_key.display()))?
+        .parse::<SshPublicKey>()
+        .context("invalid owner key")?;
+
+    if let Some(bundle_file) = &args.bundle_file {
+        return enforce_bundle(&args, &hostname, bundle_file, &owner).await;
+    }
+
+    let mut client = Client::connect(
+        &args.uds_path,
+        Path::new(&args.shm_path),
+        args.max_chans,
+        "127.0.0.1:0",
+    )
+    .await
+    .context("unable to connect to daemon")?;
+    // The manager reaches the agent at the AFC net identifier assigned to
+    // this device, which must match the daemon's AFC address.
+    info!(
+        device_id = %client.get_device_id().await?,
+        afc_addr = %client.afc_local_addr().await?,
+        %hostname,
+        "host agent started"
+    );
+
+    let last_signed_file = args.install_dir.join(LAST_SIGNED_FILE);
+    let mut last_signed = read_last_signed(&last_signed_file).await?;
+    loop {
+        let data = client.poll_afc_data().await?;
+        client.handle_afc_data(data).await?;
+
+        while let Some(msg) = client.try_recv_afc_data() {
+            let addr = msg.addr;
+            let result = handle_request(
+                &mut client,
+                &args,
+                &hostname,
+                &owner,
+                &last_signed_file,
+                &mut last_signed,
+                msg,
+            )
+            .await;
+            if let Err(err) = result {
+                warn!(%addr, "rejected request: {:#}", err);
+            }
+        }
+    }
+}
+
+/// Check and carry out one request.
+async fn handle_request(
+    client: &mut Client,
+    args: &Args,
+    hostname: &str,
+    owner: &SshPublicKey,
+    last_signed_file: &Path,
+    last_signed: &mut BTreeMap<HostArtifact, u64>,
+    msg: AfcMsg,
+) -> Result<()> {
+    ensure!(msg.label == SSH_LABEL, "channel label {} is not SSH_LABEL", msg.label);
+    let signed = SignedAgentRequest::from_bytes(&msg.data)?;
+    let request = signed.verify(owner, SystemTime::now())?.clone();
+    check_signers_are_admins(client, args.team, &signed).await?;
+
+    match request {
+        AgentRequest::Install(update) => {
+            ensure!(
+                update.hostname == hostname,
+                "update is for {}, not {}",
+                update.hostname,
+                hostname
+            );
+            if let Some(last) = last_signed.get(&update.artifact) {
+                ensure!(
+                    signed.signed_at > *last,
+                    "update signed at {} is not newer than the installed one signed at {}",
+                    signed.signed_at,
+                    last
+                );
+            }
+            validate(&update)?;
+
+            // Recorded before installing, so the request cannot be replayed
+            // even if the install fails part way
+            let mut recorded = last_signed.clone();
+            recorded.insert(update.artifact.clone(), signed.signed_at);
+            let encoded = postcard::to_allocvec(&recorded).context("unable to encode signing times")?;
+            write_atomic(last_signed_file, &encoded).await?;
+            *last_signed = recorded;
+
+            let path = artifact_path(args, &update.artifact);
+            if update.artifact == HostArtifact::Sudoers {
+                let dir = path.parent().context("sudoers file has no parent directory")?;
+                check_sudoers(dir, &update.contents).await?;
+                write_atomic_mode(&path, &update.contents, 0o440).await?;
+            } else if update.artifact == HostArtifact::Krl {
+                // Replaying an older KRL would lift revocations
+                if let Ok(installed) = fs::read(&path).await {
+                    let installed = krl_version(&installed).unwrap_or(0);
+                    let version = krl_version(&update.contents)?;
+                    ensure!(
+                        version >= installed,
+                        "KRL version {} is older than installed version {}",
+                        version,
+                        installed
+                    );
+                }
+                write_atomic(&path, &update.contents).await?;
+            } else {
+                write_atomic(&path, &update.contents).await?;
+            }
+            info!(artifact = %update.artifact, path = %path.display(), "installed update");
+        }
+        AgentRequest::Fetch {
+            hostname: requested,
+            artifact,
+        } => {
+            ensure!(
+                requested == hostname,
+                "fetch is for {}, not {}",
+                requested,
+                hostname
+            );
+            if let HostArtifact::UserKeys(login) = &artifact {
+                validate_login(login)?;
+            }
+            let path = artifact_path(args, &artifact);
+            let contents = match fs::read(&path).await {
+                Ok(contents) => Some(contents),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e).with_context(|| format!("unable to read {}", path.display())),
+            };
+            let response = AgentResponse {
+                hostname: requested,
+                artifact,
+                contents,
+            };
+            client.send_afc_data(msg.channel, &response.to_bytes()?).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Keep the installed keys in line with a grant bundle.
+///
+/// The bundle is re-read every `BUNDLE_CHECK_INTERVAL`, so a newer bundle
+/// can be dropped in place. Keys are emptied while no valid bundle is
+/// available. A failed install is retried on the next check.
+async fn enforce_bundle(
+    args: &Args,
+    hostname: &str,
+    bundle_file: &Path,
+    owner: &SshPublicKey,
+) -> Result<()> {
+    info!(%hostname, bundle = %bundle_file.display(), "enforcing grant bundle");
+
+    let issued_at_file = args.install_dir.join(BUNDLE_ISSUED_AT_FILE);
+    let mut newest = read_issued_at(&issued_at_file).await?;
+    let mut installed: Option<Option<BundleContents>> = None;
+    let mut interval = time::interval(BUNDLE_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let contents = match load_bundle(bundle_file, owner, hostname, newest).await {
+            Ok(contents) => Some(contents),
+            Err(err) => {
+                warn!("not enforcing {}: {:#}", bundle_file.display(), err);
+                None
+            }
+        };
+        if installed.as_ref() == Some(&contents) {
+            continue;
+        }
+        if let Err(err) = install_bundle(args, hostname, contents.as_ref()).await {
+            warn!("unable to install {}, retrying: {:#}", bundle_file.display(), err);
+            continue;
+        }
+        if let Some(issued_at) = contents.as_ref().map(|c| c.issued_at).filter(|t| *t > newest) {
+            // Installed already, so a failure here only weakens the rollback
+            // check until the next bundle
+            match write_atomic(&issued_at_file, issued_at.to_string().as_bytes()).await {
+                Ok(()) => newest = issued_at,
+                Err(err) => warn!("unable to record bundle issue time: {:#}", err),
+            }
+        }
+        installed = Some(contents);
+    }
+}
+
+/// Read the signing time of the last update installed for each artifact.
+async fn read_last_signed(path: &Path) -> Result<BTreeMap<HostArtifact, u64>> {
+    match fs::read(path).await {
+        Ok(bytes) => postcard::from_bytes(&bytes)
+            .with_context(|| format!("{} does not hold signing times", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e).with_context(|| format!("unable to read {}", path.display())),
+    }
+}
+
+/// Read the issue time of the newest bundle installed, 0 if none has been.
+async fn read_issued_at(path: &Path) -> Result<u64> {
+    match fs::read_to_string(path).await {
+        Ok(s) => s
+            .trim()
+            .parse()
+            .with_context(|| format!("{} is not a timestamp", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e).with_context(|| format!("unable to read {}", path.display())),
+    }
+}
+
+/// Read and verify the grant bundle for this host, refusing one issued
+/// before `min_issued_at`.
+async fn load_bundle(
+    path: &Path,
+    owner: &SshPublicKey,
+    hostname: &str,
+    min_issued_at: u64,
+) -> Result<BundleContents> {
+    let bytes = fs::read(path)
+        .await
+        .with_context(|| format!("unable to read {}", path.display()))?;
+    let bundle = GrantBundle::from_bytes(&bytes)?;
+    let contents = bundle.verify(owner, SystemTime::now())?;
+    ensure!(
+        contents.hostname == hostname,
+        "bundle is for {}, not {}",
+        contents.hostname,
+        hostname
+    );
+    ensure!(
+        contents.issued_at >= min_issued_at,
+        "bundle was issued at {}, before the installed one at {}",
+        contents.issued_at,
+        min_issued_at
+    );
+    Ok(contents.clone())
+}
+
+/// Install a bundle's keys, or empty keys for `None`.
+///
+/// Logins installed before but missing from the bundle are emptied.
+async fn install_bundle(args: &Args, hostname: &str, contents: Option<&BundleContents>) -> Result<()> {
+    let mut updates = vec![HostUpdate {
+        hostname: hostname.to_string(),
+        artifact: HostArtifact::AuthorizedKeys,
+        contents: contents.map_or(KEYS_HEADER, |c| &c.authorized_keys).as_bytes().to_vec(),
+    }];
+    let mut logins: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+    if let Ok(mut entries) = fs::read_dir(args.install_dir.join("users")).await {
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            if let Some(login) = name.to_str().filter(|l| validate_login(l).is_ok()) {
+                logins.insert(login.to_string(), KEYS_HEADER.into());
+            }
+        }
+    }
+    for (login, keys) in contents.iter().flat_map(|c| &c.user_keys) {
+        logins.insert(login.clone(), keys.clone().into_bytes());
+    }
+    updates.extend(logins.into_iter().map(|(login, keys)| HostUpdate {
+        hostname: hostname.to_string(),
+        artifact: HostArtifact::UserKeys(login),
+        contents: keys,
+    }));
+
+    // Check everything before installing anything
+    for update in &updates {
+        validate(update)?;
+    }
+    for update in &updates {
+        write_atomic(&artifact_path(args, &update.artifact), &update.contents).await?;
+    }
+    match contents {
+        Some(contents) => info!(grants = contents.grants.len(), "installed grant bundle"),
+        None => info!("emptied keys"),
+    }
+
+    Ok(())
+}
+
+/// Check that every device endorsed in a verified request's signer chain
+/// still holds `SSH_ADMIN_ROLE`.
+///
+/// Endorsements outlive role changes, so this is checked against the local
+/// replica of the team graph on every request. A request signed by the
+/// owner's key directly has no chain to check.
+async fn check_signers_are_admins(client: &mut Client, team: TeamId, signed: &SignedAgentRequest) -> Result<()> {
+    let mut queries = client.queries(team);
+    for link in &signed.chain {
+        let device_id = link
+            .device_id
+            .parse()
+            .with_context(|| format!("invalid device id `{}` in signer chain", link.device_id))?;
+        ensure!(
+            queries.device_role(device_id).await? == SSH_ADMIN_ROLE,
+            "signer {} does not hold the SSH admin role",
+            link.device_id
+        );
+    }
+    Ok(())
+}
+
+/// Reject updates that sshd would not be able to read.
+fn validate(update: &HostUpdate) -> Result<()> {
+    // The only binary artifact
+    if update.artifact == HostArtifact::Krl {
+        krl_version(&update.contents)?;
+        return Ok(());
+    }
+    let contents = std::str::from_utf8(&update.contents).context("update is not UTF-8")?;
+    let lines = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'));
+    match &update.artifact {
+        HostArtifact::AuthorizedKeys | HostArtifact::UserKeys(_) => {
+            if let HostArtifact::UserKeys(login) = &update.artifact {
+                validate_login(login)?;
+            }
+            for line in lines {
+                line.parse::<SshPublicKey>()
+                    .with_context(|| format!("invalid authorized_keys line `{}`", line))?;
+            }
+        }
+        HostArtifact::CaTrust => {
+            let keys = lines
+                .map(|line| line.parse::<SshPublicKey>())
+                .collect::<Result<Vec<_>>>()
+                .context("invalid CA key")?;
+            if keys.is_empty() {
+                bail!("update contains no CA key");
+            }
+        }
+        HostArtifact::Principals => {}
+        HostArtifact::Sudoers => {
+            sudoers_logins(contents)?;
+        }
+        // Checked above
+        HostArtifact::Krl => {}
+    }
+    Ok(())
+}
+
+/// Where an artifact is installed on this host.
+fn artifact_path(args: &Args, artifact: &HostArtifact) -> PathBuf {
+    match artifact {
+        HostArtifact::Sudoers => args.sudoers_file.clone(),
+        _ => args.install_dir.join(artifact.relative_path()),
+    }
+}
+
+/// The kernel's hostname for this machine.
+fn local_hostname() -> Result<String> {
+    let hostname =
+        std::fs::read_to_string("/proc/sys/kernel/hostname").context("unable to read hostname")?;
+    Ok(hostname.trim().to_string())
+}
@@ -0,0 +1,110 @@
+//! `AuthorizedKeysCommand` and `AuthorizedPrincipalsCommand` helper for sshd.
+//!
+//! Asks the local daemon's SSH API, which reads its replica of the team
+//! graph, for the keys or principals the local host accepts and prints them
+//! on stdout. Keys carry the same options as the rendered authorized_keys, so
+//! access levels and source restrictions apply. Devices with login mappings
+//! are only accepted for their mapped accounts. Any error or timeout prints
+//! nothing and exits non-zero, so sshd denies the login.
+//!
+//! Example `sshd_config`:
+//!
+//! ```text
+//! AuthorizedKeysCommand /usr/local/bin/aranya-ssh-keys keys %u
+//! AuthorizedKeysCommandUser aranya
+//! AuthorizedPrincipalsCommand /usr/local/bin/aranya-ssh-keys principals %i %u
+//! AuthorizedPrincipalsCommandUser aranya
+//! ```
+
+use std::{path::PathBuf, process::ExitCode, time::Duration};
+
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use tokio::time::timeout;
+
+use aranya_ssh::ssh_api::connect_ssh_api;
+
+#[derive(Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// Path to the local daemon's SSH API socket.
+    #[clap(long, default_value = "/var/run/aranya/ssh.sock")]
+    ssh_uds_path: PathBuf,
+    /// Hostname to look up, defaults to the local hostname.
+    #[clap(long)]
+    host: Option<String>,
+    /// Give up after this many milliseconds.
+    #[clap(long, default_value_t = 2000)]
+    timeout_ms: u64,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Print authorized_keys lines for the host (`AuthorizedKeysCommand`).
+    Keys {
+        /// Login name passed by sshd (`%u`).
+        user: String,
+    },
+    /// Print accepted principals for a certificate (`AuthorizedPrincipalsCommand`).
+    Principals {
+        /// Certificate key id passed by sshd (`%i`), naming the device.
+        key_id: String,
+        /// Login name passed by sshd (`%u`).
+        user: String,
+    },
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+
+    // Output is only printed once complete so a failure never yields a
+    // partial key list.
+    match timeout(Duration::from_millis(args.timeout_ms), run(&args)).await {
+        Ok(Ok(output)) => {
+            print!("{}", output);
+            ExitCode::SUCCESS
+        }
+        Ok(Err(e)) => {
+            eprintln!("aranya-ssh-keys: {:#}", e);
+            ExitCode::FAILURE
+        }
+        Err(_) => {
+            eprintln!("aranya-ssh-keys: timed out after {}ms", args.timeout_ms);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(args: &Args) -> Result<String> {
+    let hostname = match &args.host {
+        Some(host) => host.clone(),
+        None => local_hostname()?,
+    };
+    let ssh_api = connect_ssh_api(&args.ssh_uds_path).await?;
+    match &args.command {
+        Command::Keys { user } => {
+            // Rendered as the manager renders authorized_keys, with each
+            // device's grant as key options
+            Ok(ssh_api
+                .authorized_keys(tarpc::context::current(), hostname, user.clone())
+                .await??)
+        }
+        Command::Principals { key_id, user } => {
+            // Follows the same grants the CA signs certificates from
+            let principals = ssh_api
+                .principals(tarpc::context::current(), key_id.clone(), hostname, user.clone())
+                .await??;
+            Ok(principals.iter().map(|p| format!("{}\n", p)).collect())
+        }
+    }
+}
+
+/// The kernel's hostname for this machine.
+fn local_hostname() -> Result<String> {
+    let hostname =
+        std::fs::read_to_string("/proc/sys/kernel/hostname").context("unable to read hostname")?;
+    Ok(hostname.trim().to_string())
+}
@@ -0,0 +1,252 @@
+//! OpenSSH encoding of Aranya device public keys.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, ensure, Context, Result};
+use aranya_daemon::policy::KeyBundle;
+use base64::{
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD},
+    Engine as _,
+};
+use sha2::{Digest, Sha256};
+
+/// OpenSSH key type name for Ed25519 keys.
+pub const SSH_ED25519: &str = "ssh-ed25519";
+
+/// Length of a raw Ed25519 public key.
+const ED25519_KEY_LEN: usize = 32;
+
+/// An Ed25519 public key in OpenSSH form.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SshPublicKey {
+    key: [u8; ED25519_KEY_LEN],
+    comment: String,
+}
+
+impl SshPublicKey {
+    /// Create a key from a raw Ed25519 public key.
+    pub fn from_ed25519(key: [u8; ED25519_KEY_LEN], comment: impl Into<String>) -> Self {
+        Self {
+            key,
+            comment: comment.into(),
+        }
+    }
+
+    /// Create a key from an encoded Aranya Ed25519 public key.
+    pub fn from_encoded(encoded: &[u8], comment: impl Into<String>) -> Result<Self> {
+        Ok(Self::from_ed25519(decode_ed25519(encoded)?, comment))
+    }
+
+    /// Create a key from a device's public signing key.
+    pub fn from_key_bundle(keys: &KeyBundle, comment: impl Into<String>) -> Result<Self> {
+        Self::from_encoded(&keys.sign_key, comment)
+    }
+
+    /// Create a key from a device's public identity key.
+    pub fn from_identity_key(keys: &KeyBundle, comment: impl Into<String>) -> Result<Self> {
+        Self::from_encoded(&keys.ident_key, comment)
+    }
+
+    /// Parse a key from the wire format, e.g. the base64 field of a key line.
+    pub fn from_wire_format(blob: &[u8], comment: impl Into<String>) -> Result<Self> {
+        let mut rest = blob;
+        let kind = get_string(&mut rest)?;
+        ensure!(
+            kind == SSH_ED25519.as_bytes(),
+            "unsupported key type `{}`",
+            String::from_utf8_lossy(kind)
+        );
+        let raw = get_string(&mut rest)?;
+        ensure!(rest.is_empty(), "trailing data after public key");
+        ensure!(
+            raw.len() == ED25519_KEY_LEN,
+            "invalid Ed25519 key length {}",
+            raw.len()
+        );
+        let mut key = [0u8; ED25519_KEY_LEN];
+        key.copy_from_slice(raw);
+        Ok(Self::from_ed25519(key, comment))
+    }
+
+    /// The raw Ed25519 public key.
+    pub fn ed25519(&self) -> &[u8; ED25519_KEY_LEN] {
+        &self.key
+    }
+
+    /// The key comment, conventionally the device id.
+    pub fn comment(&self) -> &str {
+        &self.comment
+    }
+
+    /// The SHA256 fingerprint as printed by `ssh-keygen -l`.
+    pub fn fingerprint(&self) -> String {
+        let digest = Sha256::digest(self.wire_format());
+        format!("SHA256:{}", STANDARD_NO_PAD.encode(digest))
+    }
+
+    /// Encode the key in the SSH wire format (RFC 4253, section 6.6).
+    pub fn wire_format(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(4 + SSH_ED25519.len() + 4 + ED25519_KEY_LEN);
+        put_string(&mut buf, SSH_ED25519.as_bytes());
+        put_string(&mut buf, &self.key);
+        buf
+    }
+}
+
+/// Formats the key as an `authorized_keys` line without options.
+impl fmt::Display for SshPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", SSH_ED25519, STANDARD.encode(self.wire_format()))?;
+        if !self.comment.is_empty() {
+            write!(f, " {}", self.comment)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a `ssh-ed25519 AAAA... [comment]` line, ignoring any leading
+/// `authorized_keys` options.
+impl FromStr for SshPublicKey {
+    type Err = anyhow::Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let mut fields = line.split_whitespace();
+        fields
+            .by_ref()
+            .find(|f| *f == SSH_ED25519)
+            .with_context(|| format!("no `{}` key in `{}`", SSH_ED25519, line))?;
+        let blob = fields.next().context("missing key data")?;
+        let blob = STANDARD.decode(blob).context("invalid base64 key data")?;
+        let comment = fields.collect::<Vec<_>>().join(" ");
+        Self::from_wire_format(&blob, comment)
+    }
+}
+
+/// Extract a raw Ed25519 key from an encoded Aranya public key.
+///
+/// Public keys in a `KeyBundle` are postcard encoded, which prefixes the
+/// raw key bytes with a varint length.
+fn decode_ed25519(encoded: &[u8]) -> Result<[u8; ED25519_KEY_LEN]> {
+    let raw = match encoded {
+        [len, rest @ ..]
+            if usize::from(*len) == ED25519_KEY_LEN && rest.len() == ED25519_KEY_LEN =>
+        {
+            rest
+        }
+        raw if raw.len() == ED25519_KEY_LEN => raw,
+        _ => bail!("not an Ed25519 public key ({} bytes)", encoded.len()),
+    };
+    let mut key = [0u8; ED25519_KEY_LEN];
+    key.copy_from_slice(raw);
+    Ok(key)
+}
+
+/// Append an SSH `string` (u32 length followed by bytes) to `buf`.
+pub(crate) fn put_string(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+/// Read an SSH `string` from the front of `buf`.
+pub(crate) fn get_string<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8]> {
+    ensure!(buf.len() >= 4, "truncated length");
+    let (len, rest) = buf.split_at(4);
+    let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    ensure!(rest.len() >= len, "truncated string");
+    let (data, rest) = rest.split_at(len);
+    *buf = rest;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> SshPublicKey {
+        let mut key = [0u8; ED25519_KEY_LEN];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        SshPublicKey {
+            key,
+            comment: "alice@laptop".into(),
+        }
+    }
+
+    #[test]
+    fn wire_format_is_type_then_key() {
+        let wire = key().wire_format();
+        assert_eq!(&wire[..4], &11u32.to_be_bytes());
+        assert_eq!(&wire[4..15], SSH_ED25519.as_bytes());
+        assert_eq!(&wire[15..19], &32u32.to_be_bytes());
+        assert_eq!(&wire[19..], &key().key);
+    }
+
+    #[test]
+    fn display_matches_openssh() {
+        // Accepted by `ssh-keygen -l -f`.
+        assert_eq!(
+            key().to_string(),
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f alice@laptop",
+        );
+        let bare = SshPublicKey {
+            comment: String::new(),
+            ..key()
+        };
+        assert!(!bare.to_string().ends_with(' '));
+    }
+
+    #[test]
+    fn decode_accepts_raw_and_postcard_keys() {
+        let raw = key().key;
+        assert_eq!(decode_ed25519(&raw).unwrap(), raw);
+
+        let mut encoded = vec![ED25519_KEY_LEN as u8];
+        encoded.extend_from_slice(&raw);
+        assert_eq!(decode_ed25519(&encoded).unwrap(), raw);
+
+        assert!(decode_ed25519(&raw[..31]).is_err());
+        encoded[0] = 31;
+        assert!(decode_ed25519(&encoded).is_err());
+    }
+
+    #[test]
+    fn fingerprint_matches_ssh_keygen() {
+        assert_eq!(
+            key().fingerprint(),
+            "SHA256:ZkAslGjFiUHdGf/WUL8rQvkib4PTvQatUV0OUQSncCA",
+        );
+    }
+
+    #[test]
+    fn parse_round_trips() {
+        let line = key().to_string();
+        assert_eq!(line.parse::<SshPublicKey>().unwrap(), key());
+
+        let with_options = format!("no-pty,from=\"10.0.0.0/8\" {line}");
+        assert_eq!(with_options.parse::<SshPublicKey>().unwrap(), key());
+
+        let bare: SshPublicKey = line.rsplit_once(' ').unwrap().0.parse().unwrap();
+        assert_eq!(bare.comment(), "");
+        assert_eq!(bare.ed25519(), key().ed25519());
+    }
+
+    #[test]
+    fn parse_rejects_malformed_keys() {
+        assert!("ssh-rsa AAAAB3NzaC1yc2E".parse::<SshPublicKey>().is_err());
+        assert!("ssh-ed25519 not-base64!".parse::<SshPublicKey>().is_err());
+        assert!("ssh-ed25519".parse::<SshPublicKey>().is_err());
+
+        let wire = key().wire_format();
+        assert!(SshPublicKey::from_wire_format(&wire[..wire.len() - 1], "").is_err());
+
+        let mut trailing = wire.clone();
+        trailing.push(0);
+        assert!(SshPublicKey::from_wire_format(&trailing, "").is_err());
+
+        let mut rsa = Vec::new();
+        put_string(&mut rsa, b"ssh-rsa");
+        put_string(&mut rsa, &[0u8; ED25519_KEY_LEN]);
+        assert!(SshPublicKey::from_wire_format(&rsa, "").is_err());
+    }
+}
@@ -0,0 +1,85 @@
+//! Keeps a user's `known_hosts` in sync with the team's enrolled hosts.
+//!
+//! Fetches the pinned host keys from the local daemon's SSH API and replaces
+//! the managed block of the user's `known_hosts`. With `--interval-secs`,
+//! keeps running and re-syncs as hosts are approved or decommissioned.
+//!
+//! Pair with `StrictHostKeyChecking yes` so unknown hosts are refused rather
+//! than trusted on first use.
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use tokio::{fs, time};
+use tracing::{info, warn};
+
+use aranya_ssh::{ssh_api::connect_ssh_api, ssh_deploy::write_atomic};
+
+#[derive(Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// Path to the local daemon's SSH API socket.
+    #[clap(long, default_value = "/var/run/aranya/ssh.sock")]
+    ssh_uds_path: PathBuf,
+    /// `known_hosts` file to keep in sync, defaults to `~/.ssh/known_hosts`.
+    #[clap(long)]
+    file: Option<PathBuf>,
+    /// Re-sync every this many seconds instead of exiting after one sync.
+    #[clap(long)]
+    interval_secs: Option<u64>,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+    let args = Args::parse();
+
+    let file = match &args.file {
+        Some(file) => file.clone(),
+        None => PathBuf::from(std::env::var("HOME").context("HOME is not set")?)
+            .join(".ssh")
+            .join("known_hosts"),
+    };
+
+    let Some(interval_secs) = args.interval_secs else {
+        return sync(&args, &file).await;
+    };
+    let mut interval = time::interval(Duration::from_secs(interval_secs));
+    loop {
+        interval.tick().await;
+        // Keep the last good block in place until the daemon is back
+        if let Err(err) = sync(&args, &file).await {
+            warn!("unable to sync {}: {:#}", file.display(), err);
+        }
+    }
+}
+
+/// Replace the managed block of `file` with the daemon's pinned host keys.
+async fn sync(args: &Args, file: &Path) -> Result<()> {
+    let ssh_api = connect_ssh_api(&args.ssh_uds_path).await?;
+    let known_hosts = ssh_api.known_hosts(tarpc::context::current()).await??;
+
+    let existing = match fs::read_to_string(file).await {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("unable to read {}", file.display())),
+    };
+    let (merged, dropped) = known_hosts.merge_into(&existing)?;
+    if merged == existing {
+        return Ok(());
+    }
+
+    write_atomic(file, merged.as_bytes()).await?;
+    info!(
+        hosts = known_hosts.hostnames.len(),
+        dropped,
+        "updated {}",
+        file.display()
+    );
+
+    Ok(())
+}
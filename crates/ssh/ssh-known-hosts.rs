@@ -0,0 +1,238 @@
+//! `known_hosts` pinning enrolled hosts' keys.
+//!
+//! Managed lines live in a marked block of the user's `known_hosts`, so they
+//! can be replaced on every sync without touching the user's own entries.
+
+use std::collections::BTreeSet;
+
+use anyhow::{bail, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+
+use crate::ssh_keys::SshPublicKey;
+
+/// Marks the start of the managed block.
+const BEGIN_MARKER: &str = "# BEGIN aranya-ssh";
+/// Marks the end of the managed block.
+const END_MARKER: &str = "# END aranya-ssh";
+
+/// Managed `known_hosts` lines and the hosts they pin.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KnownHosts {
+    pub contents: String,
+    pub hostnames: BTreeSet<String>,
+}
+
+impl KnownHosts {
+    /// Pin `key` for `hostname`.
+    pub fn add_host(&mut self, hostname: &str, key: &SshPublicKey) {
+        self.contents.push_str(&known_hosts_line(hostname, key));
+        self.hostnames.insert(hostname.to_string());
+    }
+
+    /// Trust host certificates signed by `key` for hosts matching `pattern`.
+    pub fn add_cert_authority(&mut self, pattern: &str, key: &SshPublicKey) {
+        self.contents.push_str(&cert_authority_line(pattern, key));
+    }
+
+    /// Replace the managed block of `existing` with these lines.
+    ///
+    /// Entries outside the block for any pinned host are dropped, so keys
+    /// accepted on first use before a host was enrolled are no longer
+    /// trusted. Returns the new contents and the number of entries dropped.
+    pub fn merge_into(&self, existing: &str) -> Result<(String, usize)> {
+        merge_known_hosts(existing, &self.contents, &self.hostnames)
+    }
+}
+
+/// A `known_hosts` line pinning `key` for `hostname`.
+pub fn known_hosts_line(hostname: &str, key: &SshPublicKey) -> String {
+    format!("{} {}\n", hostname, key)
+}
+
+/// A `known_hosts` line trusting host certificates signed by `key` for hosts
+/// matching `pattern`.
+pub fn cert_authority_line(pattern: &str, key: &SshPublicKey) -> String {
+    format!("@cert-authority {} {}\n", pattern, key)
+}
+
+/// Replace the managed block of `existing` with `managed`.
+///
+/// Entries outside the block for any of `hostnames` are dropped, whether
+/// they name the host plainly, as `[host]:port` or hashed. Hashed entries
+/// for a non-default port cannot be matched and are kept. `@revoked` lines
+/// are always kept. Returns the new contents and the number of entries
+/// dropped.
+///
+/// Fails if a managed block is never closed, since the lines after its start
+/// may be the user's own.
+pub fn merge_known_hosts(
+    existing: &str,
+    managed: &str,
+    hostnames: &BTreeSet<String>,
+) -> Result<(String, usize)> {
+    let mut merged = String::new();
+    let mut dropped = 0;
+    let mut block_start = None;
+    for (i, line) in existing.lines().enumerate() {
+        if line.starts_with(BEGIN_MARKER) {
+            block_start = Some(i + 1);
+            continue;
+        }
+        if line.starts_with(END_MARKER) {
+            block_start = None;
+            continue;
+        }
+        if block_start.is_some() {
+            continue;
+        }
+        if names_host(line, hostnames) {
+            dropped += 1;
+            continue;
+        }
+        merged.push_str(line);
+        merged.push('\n');
+    }
+    if let Some(line) = block_start {
+        bail!("managed block starting on line {} has no `{}` line", line, END_MARKER);
+    }
+
+    merged.push_str(BEGIN_MARKER);
+    merged.push_str(" (managed, do not edit)\n");
+    merged.push_str(managed);
+    merged.push_str(END_MARKER);
+    merged.push('\n');
+
+    Ok((merged, dropped))
+}
+
+/// Reports whether a `known_hosts` line is an entry for any of `hostnames`.
+fn names_host(line: &str, hostnames: &BTreeSet<String>) -> bool {
+    let mut fields = line.split_whitespace();
+    let Some(mut names) = fields.next() else {
+        return false;
+    };
+    if names.starts_with('#') {
+        return false;
+    }
+    if let Some(marker) = names.strip_prefix('@') {
+        // Revocations only ever take trust away
+        if marker == "revoked" {
+            return false;
+        }
+        let Some(after) = fields.next() else {
+            return false;
+        };
+        names = after;
+    }
+    if let Some(hashed) = names.strip_prefix("|1|") {
+        return hashed_names_host(hashed, hostnames);
+    }
+    names.split(',').any(|name| {
+        // `[host]:port` names the host on another port
+        let host = name
+            .strip_prefix('[')
+            .and_then(|rest| rest.split_once("]:"))
+            .map_or(name, |(host, _)| host);
+        hostnames.contains(&host.to_ascii_lowercase())
+    })
+}
+
+/// Reports whether a hashed `known_hosts` name, `salt|hash` after the `|1|`
+/// prefix, is the HMAC-SHA1 of any of `hostnames`.
+fn hashed_names_host(hashed: &str, hostnames: &BTreeSet<String>) -> bool {
+    let Some((salt, hash)) = hashed.split_once('|') else {
+        return false;
+    };
+    let (Ok(salt), Ok(hash)) = (STANDARD.decode(salt), STANDARD.decode(hash)) else {
+        return false;
+    };
+    hostnames.iter().any(|hostname| {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&salt).expect("HMAC takes keys of any length");
+        mac.update(hostname.as_bytes());
+        mac.verify_slice(&hash).is_ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIC0IZq61udmN/C/ukb4ybz04LVIUhuWLw0n8ooa/RkYB";
+
+    fn hosts() -> BTreeSet<String> {
+        BTreeSet::from(["server1.example.com".to_string()])
+    }
+
+    fn managed() -> String {
+        format!("server1.example.com {}\n", KEY)
+    }
+
+    #[test]
+    fn replaces_managed_block() {
+        let existing = format!(
+            "other.example.com {key}\n{BEGIN_MARKER} (managed, do not edit)\nold.example.com {key}\n{END_MARKER}\n",
+            key = KEY
+        );
+        let (merged, dropped) = merge_known_hosts(&existing, &managed(), &hosts()).unwrap();
+        assert_eq!(
+            merged,
+            format!(
+                "other.example.com {key}\n{BEGIN_MARKER} (managed, do not edit)\nserver1.example.com {key}\n{END_MARKER}\n",
+                key = KEY
+            )
+        );
+        assert_eq!(dropped, 0);
+    }
+
+    #[test]
+    fn adds_block_to_file_without_one() {
+        let (merged, _) = merge_known_hosts("", &managed(), &hosts()).unwrap();
+        assert_eq!(
+            merged,
+            format!("{BEGIN_MARKER} (managed, do not edit)\n{}{END_MARKER}\n", managed())
+        );
+    }
+
+    #[test]
+    fn drops_entries_for_managed_hosts() {
+        let existing = [
+            format!("server1.example.com {}", KEY),
+            format!("other.example.com,SERVER1.example.com {}", KEY),
+            format!("[server1.example.com]:2222 {}", KEY),
+            // `ssh-keygen -H` of `server1.example.com`
+            format!("|1|6pUd+quGtU2M/WXWNs92v7ZbVXU=|IQKHl63z/vMY997ck37+YbXyTMs= {}", KEY),
+            format!("@cert-authority server1.example.com {}", KEY),
+        ]
+        .join("\n");
+        let (merged, dropped) = merge_known_hosts(&existing, "", &hosts()).unwrap();
+        assert_eq!(dropped, 5);
+        assert_eq!(merged, format!("{BEGIN_MARKER} (managed, do not edit)\n{END_MARKER}\n"));
+    }
+
+    #[test]
+    fn keeps_other_entries() {
+        let existing = [
+            "# server1.example.com".to_string(),
+            format!("other.example.com {}", KEY),
+            format!("[other.example.com]:2222 {}", KEY),
+            format!("!server1.example.com,*.example.com {}", KEY),
+            format!("@revoked server1.example.com {}", KEY),
+        ];
+        let (merged, dropped) = merge_known_hosts(&existing.join("\n"), "", &hosts()).unwrap();
+        assert_eq!(dropped, 0);
+        assert!(merged.starts_with(&format!("{}\n", existing.join("\n"))));
+    }
+
+    #[test]
+    fn unterminated_block_is_an_error() {
+        let existing = format!(
+            "{BEGIN_MARKER} (managed, do not edit)\nserver1.example.com {key}\nmine.example.com {key}\n",
+            key = KEY
+        );
+        let err = merge_known_hosts(&existing, &managed(), &hosts()).unwrap_err();
+        assert!(err.to_string().contains("line 1"), "{}", err);
+    }
+}
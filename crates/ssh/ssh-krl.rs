@@ -0,0 +1,226 @@
+//! OpenSSH key revocation lists revoking device certificates.
+//!
+//! KRLs follow the binary format from OpenSSH's `PROTOCOL.krl`. Certificates
+//! are revoked by key ID, whichever CA signed them. A device's key ID carries
+//! a generation that is bumped on every revocation, so certificates issued
+//! after access is granted again are not covered by the old revocation, and
+//! revoked key IDs never have to be lifted.
+
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::ssh_keys::put_string;
+
+/// `SSHKRL\n\0`, the first bytes of every KRL.
+const KRL_MAGIC: u64 = 0x5353_484b_524c_0a00;
+
+/// KRL format version.
+const KRL_FORMAT_VERSION: u32 = 1;
+
+/// Section revoking certificates.
+const KRL_SECTION_CERTIFICATES: u8 = 1;
+
+/// Certificate subsection revoking key IDs.
+const KRL_SECTION_CERT_KEY_ID: u8 = 0x23;
+
+/// Certificates revoked on one host, by key ID.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Revocations {
+    /// Incremented on every change, and carried as the KRL version.
+    pub version: u64,
+    /// Seconds since the Unix epoch of the last change.
+    pub updated_at: u64,
+    /// Revoked key IDs and when they were revoked, in seconds since the Unix
+    /// epoch.
+    pub revoked: BTreeMap<String, u64>,
+}
+
+impl Revocations {
+    /// Revoke a key ID. Returns whether it was not already revoked.
+    pub fn revoke(&mut self, key_id: &str, now: SystemTime) -> bool {
+        if self.revoked.contains_key(key_id) {
+            return false;
+        }
+        self.revoked.insert(key_id.to_string(), unix_secs(now));
+        self.bump(now);
+        true
+    }
+
+    fn bump(&mut self, now: SystemTime) {
+        self.version += 1;
+        self.updated_at = unix_secs(now);
+    }
+
+    /// Encode the revocations as a KRL for sshd's `RevokedKeys`.
+    ///
+    /// The output only depends on the revocations, so unchanged revocations
+    /// render identical KRLs.
+    pub fn to_krl(&self) -> Vec<u8> {
+        let mut krl = Vec::new();
+        krl.extend_from_slice(&KRL_MAGIC.to_be_bytes());
+        krl.extend_from_slice(&KRL_FORMAT_VERSION.to_be_bytes());
+        krl.extend_from_slice(&self.version.to_be_bytes());
+        krl.extend_from_slice(&self.updated_at.to_be_bytes());
+        // flags
+        krl.extend_from_slice(&0u64.to_be_bytes());
+        // reserved
+        put_string(&mut krl, &[]);
+        put_string(&mut krl, b"Generated by Aranya SSH Access Manager");
+
+        if !self.revoked.is_empty() {
+            let mut key_ids = Vec::new();
+            for key_id in self.revoked.keys() {
+                put_string(&mut key_ids, key_id.as_bytes());
+            }
+            let mut section = Vec::new();
+            // An empty CA key matches certificates from any CA
+            put_string(&mut section, &[]);
+            // reserved
+            put_string(&mut section, &[]);
+            section.push(KRL_SECTION_CERT_KEY_ID);
+            put_string(&mut section, &key_ids);
+
+            krl.push(KRL_SECTION_CERTIFICATES);
+            put_string(&mut krl, &section);
+        }
+
+        krl
+    }
+}
+
+/// Key ID of the certificates issued to a device in `generation`.
+///
+/// Generation 0 is the bare device id, as on certificates issued before
+/// generations were introduced.
+pub fn certificate_key_id(device_id: &str, generation: u64) -> String {
+    match generation {
+        0 => device_id.to_string(),
+        n => format!("{}/{}", device_id, n),
+    }
+}
+
+/// Key IDs of a device's certificates in every generation up to
+/// `generation`.
+pub fn device_key_ids(device_id: &str, generation: u64) -> impl Iterator<Item = String> + '_ {
+    (0..=generation).map(move |n| certificate_key_id(device_id, n))
+}
+
+/// The device id in a certificate key ID.
+pub fn key_id_device(key_id: &str) -> &str {
+    key_id.split_once('/').map_or(key_id, |(device_id, _)| device_id)
+}
+
+/// Read the version of an encoded KRL, checking its header.
+pub fn krl_version(krl: &[u8]) -> Result<u64> {
+    ensure!(krl.len() >= 20, "truncated KRL header");
+    ensure!(
+        krl[..8] == KRL_MAGIC.to_be_bytes(),
+        "not an OpenSSH KRL"
+    );
+    ensure!(
+        krl[8..12] == KRL_FORMAT_VERSION.to_be_bytes(),
+        "unsupported KRL format version"
+    );
+    let mut version = [0; 8];
+    version.copy_from_slice(&krl[12..20]);
+    Ok(u64::from_be_bytes(version))
+}
+
+/// Seconds since the Unix epoch, saturating at zero.
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process::Command};
+
+    use super::*;
+
+    // Signed by the same throwaway CA for the same user key, with key IDs
+    // `device-a`, `device-a/1` and `device-b`.
+    const CERT_A0: &str = "ssh-ed25519-cert-v01@openssh.com AAAAIHNzaC1lZDI1NTE5LWNlcnQtdjAxQG9wZW5zc2guY29tAAAAICoEPBrIBUa3I0Za80Q1w7So2pH8kOGj37h/ajPc6okQAAAAIIFcUYPLpF50l/AAYhUfFpbTcU8NerD8EzPceic9f4amAAAAAAAAAAAAAAABAAAACGRldmljZS1hAAAAFwAAABNzZXJ2ZXIxLmV4YW1wbGUuY29tAAAAAAAAAAD//////////wAAAAAAAACCAAAAFXBlcm1pdC1YMTEtZm9yd2FyZGluZwAAAAAAAAAXcGVybWl0LWFnZW50LWZvcndhcmRpbmcAAAAAAAAAFnBlcm1pdC1wb3J0LWZvcndhcmRpbmcAAAAAAAAACnBlcm1pdC1wdHkAAAAAAAAADnBlcm1pdC11c2VyLXJjAAAAAAAAAAAAAAAzAAAAC3NzaC1lZDI1NTE5AAAAIExGEeLAyelrLeXhmgLjo6Fs3npjaHQVveMgSGtLh5l5AAAAUwAAAAtzc2gtZWQyNTUxOQAAAECmXZf/3VDHOUEt4A52+BmwMYMtYj712Ge0d59aMMdhf+qzpjvHb7gJsxK8Ovaqdho0at1znsslThxPinpgcCwD";
+    const CERT_A1: &str = "ssh-ed25519-cert-v01@openssh.com AAAAIHNzaC1lZDI1NTE5LWNlcnQtdjAxQG9wZW5zc2guY29tAAAAIIHzE/CJbkYGd8WNjX+UvkUR++xpfPXeDrpCo7NZXVNnAAAAIIFcUYPLpF50l/AAYhUfFpbTcU8NerD8EzPceic9f4amAAAAAAAAAAAAAAABAAAACmRldmljZS1hLzEAAAAXAAAAE3NlcnZlcjEuZXhhbXBsZS5jb20AAAAAAAAAAP//////////AAAAAAAAAIIAAAAVcGVybWl0LVgxMS1mb3J3YXJkaW5nAAAAAAAAABdwZXJtaXQtYWdlbnQtZm9yd2FyZGluZwAAAAAAAAAWcGVybWl0LXBvcnQtZm9yd2FyZGluZwAAAAAAAAAKcGVybWl0LXB0eQAAAAAAAAAOcGVybWl0LXVzZXItcmMAAAAAAAAAAAAAADMAAAALc3NoLWVkMjU1MTkAAAAgTEYR4sDJ6Wst5eGaAuOjoWzeemNodBW94yBIa0uHmXkAAABTAAAAC3NzaC1lZDI1NTE5AAAAQJppqlfCgDJciOVnnX35/cw4/1r/mezfFHzq21+ueM+0ZJ1TarME0vZQpOdpAQrgPyOSrpufqgVFNbGD901yEA4=";
+    const CERT_B0: &str = "ssh-ed25519-cert-v01@openssh.com AAAAIHNzaC1lZDI1NTE5LWNlcnQtdjAxQG9wZW5zc2guY29tAAAAIBAPN/6I41oxaQNUnkcMpIVOtQEuUwajATmlJaglZbaJAAAAIIFcUYPLpF50l/AAYhUfFpbTcU8NerD8EzPceic9f4amAAAAAAAAAAAAAAABAAAACGRldmljZS1iAAAAFwAAABNzZXJ2ZXIxLmV4YW1wbGUuY29tAAAAAAAAAAD//////////wAAAAAAAACCAAAAFXBlcm1pdC1YMTEtZm9yd2FyZGluZwAAAAAAAAAXcGVybWl0LWFnZW50LWZvcndhcmRpbmcAAAAAAAAAFnBlcm1pdC1wb3J0LWZvcndhcmRpbmcAAAAAAAAACnBlcm1pdC1wdHkAAAAAAAAADnBlcm1pdC11c2VyLXJjAAAAAAAAAAAAAAAzAAAAC3NzaC1lZDI1NTE5AAAAIExGEeLAyelrLeXhmgLjo6Fs3npjaHQVveMgSGtLh5l5AAAAUwAAAAtzc2gtZWQyNTUxOQAAAEBp3JpGnRuMZ1/rExouYH87CIJCBaLDqMhZnRcja5VREGfz+Si4/hnFj4chneL8Wiph8OkjJJPxRJ+v5GY8T/oE";
+
+    /// Ask `ssh-keygen -Q` which of `certs` a KRL revokes.
+    fn revoked_by(name: &str, krl: &[u8], certs: &[&str]) -> Vec<bool> {
+        let dir = std::env::temp_dir().join(format!("aranya-ssh-krl-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let krl_file = dir.join("krl");
+        fs::write(&krl_file, krl).unwrap();
+
+        let revoked = certs
+            .iter()
+            .enumerate()
+            .map(|(i, cert)| {
+                let cert_file = dir.join(format!("{}-cert.pub", i));
+                fs::write(&cert_file, format!("{}\n", cert)).unwrap();
+                let output = Command::new("ssh-keygen")
+                    .arg("-Q")
+                    .arg("-f")
+                    .arg(&krl_file)
+                    .arg(&cert_file)
+                    .output()
+                    .expect("ssh-keygen is needed to check KRLs");
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                // Exit status 1 with `REVOKED` for revoked keys, 0 with `ok`
+                assert!(
+                    output.status.success() || stdout.contains("REVOKED"),
+                    "ssh-keygen rejected the KRL: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                );
+                !output.status.success()
+            })
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        revoked
+    }
+
+    #[test]
+    fn empty_krl_revokes_nothing() {
+        let krl = Revocations::default().to_krl();
+        assert_eq!(revoked_by("empty", &krl, &[CERT_A0, CERT_B0]), [false, false]);
+    }
+
+    #[test]
+    fn revokes_key_ids_from_any_ca() {
+        let mut revocations = Revocations::default();
+        let now = SystemTime::now();
+        for key_id in device_key_ids("device-a", 0) {
+            assert!(revocations.revoke(&key_id, now));
+        }
+        let krl = revocations.to_krl();
+        assert_eq!(krl_version(&krl).unwrap(), 1);
+        assert_eq!(
+            revoked_by("revoked", &krl, &[CERT_A0, CERT_A1, CERT_B0]),
+            [true, false, false]
+        );
+
+        // The next generation's certificate is only revoked with it
+        for key_id in device_key_ids("device-a", 1) {
+            revocations.revoke(&key_id, now);
+        }
+        let krl = revocations.to_krl();
+        assert_eq!(krl_version(&krl).unwrap(), 2);
+        assert_eq!(
+            revoked_by("next", &krl, &[CERT_A0, CERT_A1, CERT_B0]),
+            [true, true, false]
+        );
+    }
+
+    #[test]
+    fn key_ids_name_their_device() {
+        assert_eq!(certificate_key_id("device-a", 0), "device-a");
+        assert_eq!(certificate_key_id("device-a", 2), "device-a/2");
+        assert_eq!(key_id_device("device-a"), "device-a");
+        assert_eq!(key_id_device("device-a/2"), "device-a");
+    }
+}
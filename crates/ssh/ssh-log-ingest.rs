@@ -0,0 +1,347 @@
+//! Publishes sshd logins as audit records to a collector device.
+//!
+//! Tails an sshd auth log, either a syslog file such as `/var/log/auth.log`
+//! or a file a `journalctl -o export` stream is appended to:
+//!
+//! ```text
+//! journalctl -f -o export -u ssh >> /var/log/sshd.export
+//! ```
+//!
+//! Any local user can write syslog lines tagged `sshd`, so records read from
+//! a syslog file can be forged from the host. Journal entries are attributed
+//! by the fields journald sets itself, so prefer the export format.
+//!
+//! Each accepted or failed login is attributed to a team device by its key
+//! fingerprint, or by the key ID of a certificate signed by the team CA in
+//! `--ca-key`, and sent over an AFC channel on `SSH_AUDIT_LABEL`. Records are
+//! queued while the collector is unreachable.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use aranya_client::{AfcId, Client};
+use aranya_daemon_api::{NetIdentifier, TeamId};
+use clap::{Parser, ValueEnum};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, SeekFrom},
+    time,
+};
+use tracing::{info, warn};
+
+use aranya_ssh::{
+    ssh_aranya::SSH_AUDIT_LABEL,
+    ssh_audit::{
+        next_journal_entry, parse_journal_entry, parse_sshd_message, parse_syslog_line,
+        AuditRecord, LoginEvent,
+    },
+    ssh_keys::SshPublicKey,
+    ssh_krl::key_id_device,
+};
+
+/// How often the log is checked for new data.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Minimum time between refreshes of the fingerprint map.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Records kept while the collector is unreachable. The oldest are dropped
+/// beyond this.
+const MAX_QUEUED: usize = 10_000;
+
+#[derive(Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// Path to the daemon's UDS API socket.
+    #[clap(long, default_value = "/var/run/aranya/uds.sock")]
+    uds_path: PathBuf,
+    /// Path to the daemon's AFC shared memory.
+    #[clap(long, default_value = "/afc")]
+    shm_path: String,
+    /// Maximum number of AFC channels.
+    #[clap(long, default_value_t = 100)]
+    max_chans: usize,
+    /// Team the host and collector are on.
+    #[clap(long)]
+    team: TeamId,
+    /// Hostname put on records, defaults to the local hostname.
+    #[clap(long)]
+    host: Option<String>,
+    /// AFC address of the collector device.
+    #[clap(long)]
+    collector: String,
+    /// Log to read.
+    #[clap(long, default_value = "/var/log/auth.log")]
+    file: PathBuf,
+    #[clap(long, value_enum, default_value_t = LogFormat::Syslog)]
+    format: LogFormat,
+    /// The team CA's public key, as installed for `TrustedUserCAKeys`.
+    #[clap(long, default_value = "/etc/ssh/aranya/ca.pub")]
+    ca_key: PathBuf,
+    /// Read the log from the start instead of only new entries.
+    #[clap(long)]
+    from_start: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    /// One syslog line per message.
+    Syslog,
+    /// `journalctl -o export` entries.
+    JournalExport,
+}
+
+/// Maps key fingerprints to team devices.
+struct Devices {
+    fingerprints: BTreeMap<String, String>,
+    refreshed_at: Option<Instant>,
+    /// File holding the team CA's public key.
+    ca_key: PathBuf,
+}
+
+impl Devices {
+    /// Attribute a login to a device, refreshing the map for unknown keys.
+    async fn lookup(&mut self, client: &mut Client, team: TeamId, event: &LoginEvent) -> Option<String> {
+        // Certificates from the team CA carry the device id. Other CAs the
+        // host trusts could put any id there, so those fall back to the key.
+        if let (Some(key_id), Some(ca)) = (&event.key_id, &event.ca_fingerprint) {
+            match self.ca_fingerprint().await {
+                Ok(team_ca) if *ca == team_ca => return Some(key_id_device(key_id).to_string()),
+                Ok(_) => {}
+                Err(err) => warn!("unable to read team CA key: {:#}", err),
+            }
+        }
+        let fingerprint = event.fingerprint.as_ref()?;
+        if !self.fingerprints.contains_key(fingerprint)
+            && self.refreshed_at.is_none_or(|t| t.elapsed() >= REFRESH_INTERVAL)
+        {
+            if let Err(err) = self.refresh(client, team).await {
+                warn!("unable to refresh device keys: {:#}", err);
+            }
+        }
+        self.fingerprints.get(fingerprint).cloned()
+    }
+
+    /// Fingerprint of the team CA key, read on every use since the manager
+    /// may replace it.
+    async fn ca_fingerprint(&self) -> Result<String> {
+        let contents = tokio::fs::read_to_string(&self.ca_key)
+            .await
+            .with_context(|| format!("unable to read {}", self.ca_key.display()))?;
+        let key: SshPublicKey = contents.trim().parse()?;
+        Ok(key.fingerprint())
+    }
+
+    /// Rebuild the map from the team's devices.
+    async fn refresh(&mut self, client: &mut Client, team: TeamId) -> Result<()> {
+        self.refreshed_at = Some(Instant::now());
+        let mut queries = client.queries(team);
+        let mut fingerprints = BTreeMap::new();
+        for device_id in queries.devices_on_team().await?.iter() {
+            let keys = queries.device_keybundle(*device_id).await?;
+            let Ok(key) = SshPublicKey::from_encoded(&keys.signing, device_id.to_string()) else {
+                continue;
+            };
+            fingerprints.insert(key.fingerprint(), device_id.to_string());
+        }
+        self.fingerprints = fingerprints;
+        Ok(())
+    }
+}
+
+/// Sends records to the collector, queueing them while it is unreachable.
+struct Publisher {
+    team: TeamId,
+    collector: NetIdentifier,
+    channel: Option<AfcId>,
+    queue: VecDeque<AuditRecord>,
+}
+
+impl Publisher {
+    /// Queue a record and send everything queued.
+    async fn publish(&mut self, client: &mut Client, record: AuditRecord) {
+        if self.queue.len() == MAX_QUEUED {
+            self.queue.pop_front();
+            warn!("audit queue full, dropped oldest record");
+        }
+        self.queue.push_back(record);
+        if let Err(err) = self.flush(client).await {
+            warn!(queued = self.queue.len(), "unable to reach collector: {:#}", err);
+        }
+    }
+
+    async fn flush(&mut self, client: &mut Client) -> Result<()> {
+        while let Some(record) = self.queue.front() {
+            let id = match self.channel {
+                Some(id) => id,
+                None => {
+                    let id = client
+                        .create_afc_bidi_channel(self.team, self.collector.clone(), SSH_AUDIT_LABEL)
+                        .await
+                        .context("unable to open AFC channel to collector")?;
+                    *self.channel.insert(id)
+                }
+            };
+            if let Err(err) = client.send_afc_data(id, &record.to_bytes()?).await {
+                // The collector may have restarted, so reopen the channel
+                self.channel = None;
+                return Err(err.into());
+            }
+            self.queue.pop_front();
+        }
+        Ok(())
+    }
+}
+
+/// Reads data appended to a file, reopening it when it is rotated.
+struct Tail {
+    path: PathBuf,
+    file: File,
+    pos: u64,
+}
+
+impl Tail {
+    async fn open(path: &Path, from_start: bool) -> Result<Self> {
+        let mut file = File::open(path)
+            .await
+            .with_context(|| format!("unable to open {}", path.display()))?;
+        let pos = if from_start {
+            0
+        } else {
+            file.seek(SeekFrom::End(0)).await?
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            pos,
+        })
+    }
+
+    /// Read whatever was appended since the last read.
+    ///
+    /// A truncated file is read again from the start. Once the path refers
+    /// to a new file, the rest of the old one is read before switching.
+    async fn read(&mut self) -> Result<Vec<u8>> {
+        let opened = self.file.metadata().await?;
+        if opened.len() < self.pos {
+            self.pos = self.file.seek(SeekFrom::Start(0)).await?;
+        }
+
+        let mut data = self.read_rest().await?;
+
+        // Until the new file is created, keep the old one
+        let rotated = tokio::fs::metadata(&self.path)
+            .await
+            .is_ok_and(|m| m.ino() != opened.ino());
+        if rotated {
+            info!(file = %self.path.display(), "log rotated, reopening");
+            *self = Self::open(&self.path, true).await?;
+            data.extend(self.read_rest().await?);
+        }
+        Ok(data)
+    }
+
+    /// Read from the current position to the end of the opened file.
+    async fn read_rest(&mut self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.file.read_to_end(&mut data).await?;
+        self.pos += data.len() as u64;
+        Ok(data)
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+    let args = Args::parse();
+
+    let hostname = match &args.host {
+        Some(host) => host.clone(),
+        None => std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .context("unable to read hostname")?
+            .trim()
+            .to_string(),
+    };
+
+    let mut client = Client::connect(
+        &args.uds_path,
+        Path::new(&args.shm_path),
+        args.max_chans,
+        "127.0.0.1:0",
+    )
+    .await
+    .context("unable to connect to daemon")?;
+
+    let mut devices = Devices {
+        fingerprints: BTreeMap::new(),
+        refreshed_at: None,
+        ca_key: args.ca_key.clone(),
+    };
+    let mut publisher = Publisher {
+        team: args.team,
+        collector: NetIdentifier(args.collector.clone()),
+        channel: None,
+        queue: VecDeque::new(),
+    };
+    let mut tail = Tail::open(&args.file, args.from_start).await?;
+    info!(%hostname, file = %args.file.display(), format = ?args.format, "ingesting sshd logins");
+
+    let mut buf = Vec::new();
+    let mut interval = time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        buf.extend(tail.read().await?);
+
+        for (logged_at, message) in take_messages(&mut buf, args.format) {
+            let Some(event) = parse_sshd_message(&message) else {
+                continue;
+            };
+            let device_id = devices.lookup(&mut client, args.team, &event).await;
+            let record = AuditRecord {
+                hostname: hostname.clone(),
+                logged_at,
+                observed_at: SystemTime::now(),
+                event,
+                device_id,
+            };
+            publisher.publish(&mut client, record).await;
+        }
+        // Retry records queued while the collector was unreachable
+        if !publisher.queue.is_empty() {
+            let _ = publisher.flush(&mut client).await;
+        }
+    }
+}
+
+/// Take the complete messages from the front of `buf`, leaving a partial
+/// line or entry for the next read.
+fn take_messages(buf: &mut Vec<u8>, format: LogFormat) -> Vec<(Option<SystemTime>, String)> {
+    let mut messages = Vec::new();
+    let mut consumed = 0;
+    match format {
+        LogFormat::Syslog => {
+            while let Some(end) = buf[consumed..].iter().position(|&b| b == b'\n') {
+                let line = String::from_utf8_lossy(&buf[consumed..consumed + end]);
+                if let Some((logged_at, message)) = parse_syslog_line(&line) {
+                    messages.push((logged_at, message.to_string()));
+                }
+                consumed += end + 1;
+            }
+        }
+        LogFormat::JournalExport => {
+            while let Some((entry, len)) = next_journal_entry(&buf[consumed..]) {
+                if let Some((logged_at, message)) = parse_journal_entry(&entry) {
+                    messages.push((logged_at, message.to_string()));
+                }
+                consumed += len;
+            }
+        }
+    }
+    buf.drain(..consumed);
+    messages
+}
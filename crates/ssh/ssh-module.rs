@@ -7,11 +7,19 @@ where
     /// Grants SSH access to a user for a specific host
     fn grant_ssh_access(&self, 
                        user_id: UserId, 
-                       host: String,
-                       access_level: SshAccessLevel
+                       host_label: Label,
+                       grant: SshGrant
     ) -> impl Future<Output = Result<Vec<Effect>>> + Send {
         self.with_actor(move |actor| {
-            actor.grant_ssh_access(user_id.into(), host, access_level)?;
+            let command = grant.level.command().unwrap_or_default().to_string();
+            actor.grant_ssh_access(
+                user_id.into(),
+                i64::from(host_label.to_u32()),
+                grant.level.to_policy(),
+                command,
+                grant.from.join(","),
+                grant.expires_at_policy(),
+            )?;
             Ok(())
         })
     }
@@ -19,11 +27,336 @@ where
     /// Revokes SSH access from a user for a specific host
     fn revoke_ssh_access(&self,
                         user_id: UserId,
-                        host: String
+                        host_label: Label
     ) -> impl Future<Output = Result<Vec<Effect>>> + Send {
         self.with_actor(move |actor| {
-            actor.revoke_ssh_access(user_id.into(), host)?;
+            actor.revoke_ssh_access(user_id.into(), i64::from(host_label.to_u32()))?;
             Ok(())
         })
     }
-}
\ No newline at end of file
+    
+    /// Lists the grants on a host
+    fn query_ssh_grants_for_host_off_graph(&self,
+                                           host_label: Label
+    ) -> impl Future<Output = Result<(Vec<Box<[u8]>>, Vec<Effect>)>> + Send {
+        self.session_action(move || VmAction {
+            name: "query_ssh_grants_for_host",
+            args: Cow::Owned(vec![Value::from(i64::from(host_label.to_u32()))]),
+        })
+    }
+    
+    /// Lists the grants held by a user
+    fn query_ssh_grants_for_device_off_graph(&self,
+                                             user_id: UserId
+    ) -> impl Future<Output = Result<(Vec<Box<[u8]>>, Vec<Effect>)>> + Send {
+        self.session_action(move || VmAction {
+            name: "query_ssh_grants_for_device",
+            args: Cow::Owned(vec![Value::from(Id::from(user_id))]),
+        })
+    }
+    
+    /// Lists every grant on the team
+    fn query_ssh_grants_off_graph(&self) -> impl Future<Output = Result<(Vec<Box<[u8]>>, Vec<Effect>)>> + Send {
+        self.session_action(move || VmAction {
+            name: "query_ssh_grants",
+            args: Cow::Owned(vec![]),
+        })
+    }
+    
+    /// Registers a host's unique label in the team's host registry
+    fn register_ssh_host(&self,
+                         hostname: String,
+                         label: Label
+    ) -> impl Future<Output = Result<Vec<Effect>>> + Send {
+        self.with_actor(move |actor| {
+            actor.register_ssh_host(hostname, i64::from(label.to_u32()))?;
+            Ok(())
+        })
+    }
+    
+    /// Looks up a registered host by hostname
+    fn query_ssh_host_by_name_off_graph(&self,
+                                        hostname: String
+    ) -> impl Future<Output = Result<(Vec<Box<[u8]>>, Vec<Effect>)>> + Send {
+        self.session_action(move || VmAction {
+            name: "query_ssh_host_by_name",
+            args: Cow::Owned(vec![Value::from(hostname)]),
+        })
+    }
+    
+    /// Looks up a registered host by label
+    fn query_ssh_host_by_label_off_graph(&self,
+                                         label: Label
+    ) -> impl Future<Output = Result<(Vec<Box<[u8]>>, Vec<Effect>)>> + Send {
+        self.session_action(move || VmAction {
+            name: "query_ssh_host_by_label",
+            args: Cow::Owned(vec![Value::from(i64::from(label.to_u32()))]),
+        })
+    }
+    
+    /// Lists every registered host
+    fn query_ssh_hosts_off_graph(&self) -> impl Future<Output = Result<(Vec<Box<[u8]>>, Vec<Effect>)>> + Send {
+        self.session_action(move || VmAction {
+            name: "query_ssh_hosts",
+            args: Cow::Owned(vec![]),
+        })
+    }
+    
+    /// Lists the labels of decommissioned hosts
+    fn query_ssh_retired_host_labels_off_graph(&self) -> impl Future<Output = Result<(Vec<Box<[u8]>>, Vec<Effect>)>> + Send {
+        self.session_action(move || VmAction {
+            name: "query_ssh_retired_host_labels",
+            args: Cow::Owned(vec![]),
+        })
+    }
+    
+    /// Sets how many SSH admins must approve break-glass access
+    fn set_ssh_break_glass_threshold(&self,
+                                     approvals: u32
+    ) -> impl Future<Output = Result<Vec<Effect>>> + Send {
+        self.with_actor(move |actor| {
+            actor.set_ssh_break_glass_threshold(i64::from(approvals))?;
+            Ok(())
+        })
+    }
+    
+    /// Requests emergency access to a host
+    fn request_ssh_break_glass(&self,
+                               host_label: Label,
+                               ttl: Duration,
+                               reason: String,
+                               requested_at: SystemTime
+    ) -> impl Future<Output = Result<Vec<Effect>>> + Send {
+        self.with_actor(move |actor| {
+            let ttl = i64::try_from(ttl.as_secs()).unwrap_or(i64::MAX);
+            let requested_at = requested_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            actor.request_ssh_break_glass(
+                i64::from(host_label.to_u32()),
+                ttl,
+                reason,
+                i64::try_from(requested_at).unwrap_or(i64::MAX),
+            )?;
+            Ok(())
+        })
+    }
+    
+    /// Approves a pending break-glass request
+    fn approve_ssh_break_glass(&self,
+                               request_id: Id,
+                               now: SystemTime
+    ) -> impl Future<Output = Result<Vec<Effect>>> + Send {
+        self.with_actor(move |actor| {
+            let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            actor.approve_ssh_break_glass(request_id, i64::try_from(now).unwrap_or(i64::MAX))?;
+            Ok(())
+        })
+    }
+    
+    /// Lists every break-glass request with its approval count
+    fn query_ssh_break_glass_requests_off_graph(&self) -> impl Future<Output = Result<(Vec<Box<[u8]>>, Vec<Effect>)>> + Send {
+        self.session_action(move || VmAction {
+            name: "query_ssh_break_glass_requests",
+            args: Cow::Owned(vec![]),
+        })
+    }
+    
+    /// Lists every activated break-glass grant
+    fn query_ssh_break_glass_active_off_graph(&self) -> impl Future<Output = Result<(Vec<Box<[u8]>>, Vec<Effect>)>> + Send {
+        self.session_action(move || VmAction {
+            name: "query_ssh_break_glass_active",
+            args: Cow::Owned(vec![]),
+        })
+    }
+    
+    /// Sets a tag on a registered host
+    fn set_ssh_host_tag(&self,
+                        host_label: Label,
+                        tag: HostTag
+    ) -> impl Future<Output = Result<Vec<Effect>>> + Send {
+        self.with_actor(move |actor| {
+            actor.set_ssh_host_tag(i64::from(host_label.to_u32()), tag.key, tag.value)?;
+            Ok(())
+        })
+    }
+    
+    /// Removes a tag from a registered host
+    fn remove_ssh_host_tag(&self,
+                           host_label: Label,
+                           key: String
+    ) -> impl Future<Output = Result<Vec<Effect>>> + Send {
+        self.with_actor(move |actor| {
+            actor.remove_ssh_host_tag(i64::from(host_label.to_u32()), key)?;
+            Ok(())
+        })
+    }
+    
+    /// Grants SSH access to every host carrying a tag
+    fn grant_ssh_group_access(&self,
+                              user_id: UserId,
+                              tag: HostTag,
+                              grant: SshGrant
+    ) -> impl Future<Output = Result<Vec<Effect>>> + Send {
+        self.with_actor(move |actor| {
+            let command = grant.level.command().unwrap_or_default().to_string();
+            actor.grant_ssh_group_access(
+                user_id.into(),
+                tag.key,
+                tag.value,
+                grant.level.to_policy(),
+                command,
+                grant.from.join(","),
+                grant.expires_at_policy(),
+            )?;
+            Ok(())
+        })
+    }
+    
+    /// Revokes a group grant
+    fn revoke_ssh_group_access(&self,
+                               user_id: UserId,
+                               tag: HostTag
+    ) -> impl Future<Output = Result<Vec<Effect>>> + Send {
+        self.with_actor(move |actor| {
+            actor.revoke_ssh_group_access(user_id.into(), tag.key, tag.value)?;
+            Ok(())
+        })
+    }
+    
+    /// Lists every host tag
+    fn query_ssh_host_tags_off_graph(&self) -> impl Future<Output = Result<(Vec<Box<[u8]>>, Vec<Effect>)>> + Send {
+        self.session_action(move || VmAction {
+            name: "query_ssh_host_tags",
+            args: Cow::Owned(vec![]),
+        })
+    }
+    
+    /// Lists every group grant
+    fn query_ssh_group_grants_off_graph(&self) -> impl Future<Output = Result<(Vec<Box<[u8]>>, Vec<Effect>)>> + Send {
+        self.session_action(move || VmAction {
+            name: "query_ssh_group_grants",
+            args: Cow::Owned(vec![]),
+        })
+    }
+    
+    /// Allows a device to log in as `login`, on every host or on hosts
+    /// carrying `group`
+    fn allow_ssh_login(&self,
+                       user_id: UserId,
+                       login: String,
+                       group: Option<HostTag>
+    ) -> impl Future<Output = Result<Vec<Effect>>> + Send {
+        self.with_actor(move |actor| {
+            let group = group.unwrap_or_else(|| HostTag::new("", ""));
+            actor.allow_ssh_login(user_id.into(), login, group.key, group.value)?;
+            Ok(())
+        })
+    }
+    
+    /// Removes a login mapping
+    fn disallow_ssh_login(&self,
+                          user_id: UserId,
+                          login: String,
+                          group: Option<HostTag>
+    ) -> impl Future<Output = Result<Vec<Effect>>> + Send {
+        self.with_actor(move |actor| {
+            let group = group.unwrap_or_else(|| HostTag::new("", ""));
+            actor.disallow_ssh_login(user_id.into(), login, group.key, group.value)?;
+            Ok(())
+        })
+    }
+    
+    /// Lists every login mapping
+    fn query_ssh_logins_off_graph(&self) -> impl Future<Output = Result<(Vec<Box<[u8]>>, Vec<Effect>)>> + Send {
+        self.session_action(move || VmAction {
+            name: "query_ssh_logins",
+            args: Cow::Owned(vec![]),
+        })
+    }
+    
+    /// Enrolls the calling device as the host `hostname`
+    fn enroll_ssh_host(&self,
+                       hostname: String,
+                       host_key: SshPublicKey
+    ) -> impl Future<Output = Result<Vec<Effect>>> + Send {
+        self.with_actor(move |actor| {
+            actor.enroll_ssh_host(hostname, host_key.to_string())?;
+            Ok(())
+        })
+    }
+    
+    /// Approves an enrolled host
+    fn approve_ssh_host(&self, hostname: String) -> impl Future<Output = Result<Vec<Effect>>> + Send {
+        self.with_actor(move |actor| {
+            actor.approve_ssh_host(hostname)?;
+            Ok(())
+        })
+    }
+    
+    /// Removes a host from the inventory
+    fn decommission_ssh_host(&self, hostname: String) -> impl Future<Output = Result<Vec<Effect>>> + Send {
+        self.with_actor(move |actor| {
+            actor.decommission_ssh_host(hostname)?;
+            Ok(())
+        })
+    }
+    
+    /// Lists every enrolled host
+    fn query_ssh_host_enrollments_off_graph(&self) -> impl Future<Output = Result<(Vec<Box<[u8]>>, Vec<Effect>)>> + Send {
+        self.session_action(move || VmAction {
+            name: "query_ssh_host_enrollments",
+            args: Cow::Owned(vec![]),
+        })
+    }
+    
+    /// Requests access to a host for `duration`
+    fn request_ssh_access(&self,
+                          host_label: Label,
+                          level: SshAccessLevel,
+                          duration: Duration,
+                          reason: String
+    ) -> impl Future<Output = Result<Vec<Effect>>> + Send {
+        self.with_actor(move |actor| {
+            let command = level.command().unwrap_or_default().to_string();
+            let duration = i64::try_from(duration.as_secs()).unwrap_or(i64::MAX);
+            actor.request_ssh_access(
+                i64::from(host_label.to_u32()),
+                level.to_policy(),
+                command,
+                duration,
+                reason,
+            )?;
+            Ok(())
+        })
+    }
+    
+    /// Approves a pending access request, turning it into a grant
+    fn approve_ssh_access_request(&self,
+                                  request_id: Id,
+                                  now: SystemTime
+    ) -> impl Future<Output = Result<Vec<Effect>>> + Send {
+        self.with_actor(move |actor| {
+            let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            actor.approve_ssh_access_request(request_id, i64::try_from(now).unwrap_or(i64::MAX))?;
+            Ok(())
+        })
+    }
+    
+    /// Denies a pending access request
+    fn deny_ssh_access_request(&self,
+                               request_id: Id,
+                               reason: String
+    ) -> impl Future<Output = Result<Vec<Effect>>> + Send {
+        self.with_actor(move |actor| {
+            actor.deny_ssh_access_request(request_id, reason)?;
+            Ok(())
+        })
+    }
+    
+    /// Lists every pending access request
+    fn query_ssh_access_requests_off_graph(&self) -> impl Future<Output = Result<(Vec<Box<[u8]>>, Vec<Effect>)>> + Send {
+        self.session_action(move || VmAction {
+            name: "query_ssh_access_requests",
+            args: Cow::Owned(vec![]),
+        })
+    }
+}
@@ -0,0 +1,213 @@
+//! Reviewable plans of SSH access changes.
+//!
+//! `SshAccessManager::plan` renders the authorized_keys files and sudoers
+//! fragment each host would receive after a set of grants and revokes,
+//! without changing the team graph or any host, and `SshAccessManager::apply`
+//! carries the plan out.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use aranya_crypto::UserId;
+
+use crate::{ssh_access::SshGrant, ssh_deploy::HostArtifact};
+
+/// A grant or revoke to include in a plan.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AccessChange {
+    /// `SshAccessManager::grant_host_access`.
+    Grant {
+        user_id: UserId,
+        hostname: String,
+        grant: SshGrant,
+    },
+    /// `SshAccessManager::revoke_host_access`.
+    Revoke { user_id: UserId, hostname: String },
+}
+
+impl AccessChange {
+    /// The host the change applies to.
+    pub fn hostname(&self) -> &str {
+        match self {
+            Self::Grant { hostname, .. } | Self::Revoke { hostname, .. } => hostname,
+        }
+    }
+
+    /// The device's direct grant once the change is made, `None` for a
+    /// revoke.
+    pub(crate) fn pending_grant(&self) -> (UserId, Option<SshGrant>) {
+        match self {
+            Self::Grant { user_id, grant, .. } => (*user_id, Some(grant.clone())),
+            Self::Revoke { user_id, .. } => (*user_id, None),
+        }
+    }
+}
+
+impl fmt::Display for AccessChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Grant {
+                user_id,
+                hostname,
+                grant,
+            } => write!(f, "grant {:?} on {} to {}", grant.level, hostname, user_id),
+            Self::Revoke { user_id, hostname } => {
+                write!(f, "revoke {} from {}", hostname, user_id)
+            }
+        }
+    }
+}
+
+/// How a plan changes one host.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HostPlan {
+    /// Lines the host's files would gain, by file. Files that would not
+    /// change are left out.
+    pub added: BTreeMap<HostArtifact, Vec<String>>,
+    /// Lines the host's files would lose, by file.
+    pub removed: BTreeMap<HostArtifact, Vec<String>>,
+    /// Devices that would gain access, and the host as a certificate
+    /// principal.
+    pub gained: BTreeSet<UserId>,
+    /// Devices that would lose access, and the host as a certificate
+    /// principal.
+    pub lost: BTreeSet<UserId>,
+}
+
+impl HostPlan {
+    /// Reports whether the plan leaves the host unchanged, in its keys and
+    /// in who may log in to it.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.gained.is_empty()
+            && self.lost.is_empty()
+    }
+}
+
+/// A set of changes and their effect on every host they touch.
+///
+/// Hosts whose deployed files are out of date with the team graph are
+/// included even when no change names them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AccessPlan {
+    pub changes: Vec<AccessChange>,
+    /// Hosts that would change, by hostname.
+    pub hosts: BTreeMap<String, HostPlan>,
+}
+
+impl AccessPlan {
+    /// Reports whether applying the plan would change no host.
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+}
+
+/// Formats the plan as a unified-diff style listing per host.
+impl fmt::Display for AccessPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for change in &self.changes {
+            writeln!(f, "# {}", change)?;
+        }
+        for (hostname, plan) in &self.hosts {
+            let artifacts: BTreeSet<_> = plan.removed.keys().chain(plan.added.keys()).collect();
+            for artifact in artifacts {
+                writeln!(f, "--- {}/{}", hostname, artifact)?;
+                writeln!(f, "+++ {}/{}", hostname, artifact)?;
+                for line in plan.removed.get(artifact).into_iter().flatten() {
+                    writeln!(f, "-{}", line)?;
+                }
+                for line in plan.added.get(artifact).into_iter().flatten() {
+                    writeln!(f, "+{}", line)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh_access::SshAccessLevel;
+
+    #[test]
+    fn host_plans_with_access_changes_are_not_empty() {
+        assert!(HostPlan::default().is_empty());
+        let plan = HostPlan {
+            gained: BTreeSet::from([UserId::default()]),
+            ..HostPlan::default()
+        };
+        assert!(!plan.is_empty());
+        let plan = HostPlan {
+            lost: BTreeSet::from([UserId::default()]),
+            ..HostPlan::default()
+        };
+        assert!(!plan.is_empty());
+    }
+
+    #[test]
+    fn plans_render_changes_then_a_diff_per_host() {
+        let user_id = UserId::default();
+        let plan = AccessPlan {
+            changes: vec![
+                AccessChange::Grant {
+                    user_id,
+                    hostname: "db1".to_string(),
+                    grant: SshGrant::new(SshAccessLevel::Standard),
+                },
+                AccessChange::Revoke {
+                    user_id,
+                    hostname: "web1".to_string(),
+                },
+            ],
+            hosts: BTreeMap::from([
+                (
+                    "db1".to_string(),
+                    HostPlan {
+                        added: BTreeMap::from([(
+                            HostArtifact::UserKeys("deploy".to_string()),
+                            vec!["restrict,pty ssh-ed25519 AAAA new".to_string()],
+                        )]),
+                        removed: BTreeMap::from([(
+                            HostArtifact::AuthorizedKeys,
+                            vec!["ssh-ed25519 AAAA old".to_string()],
+                        )]),
+                        ..HostPlan::default()
+                    },
+                ),
+                (
+                    "web1".to_string(),
+                    HostPlan {
+                        removed: BTreeMap::from([(
+                            HostArtifact::AuthorizedKeys,
+                            vec!["ssh-ed25519 AAAA web".to_string()],
+                        )]),
+                        ..HostPlan::default()
+                    },
+                ),
+            ]),
+        };
+
+        assert_eq!(
+            plan.to_string(),
+            format!(
+                "# grant Standard on db1 to {user_id}\n\
+                 # revoke web1 from {user_id}\n\
+                 --- db1/authorized_keys\n\
+                 +++ db1/authorized_keys\n\
+                 -ssh-ed25519 AAAA old\n\
+                 --- db1/users/deploy\n\
+                 +++ db1/users/deploy\n\
+                 +restrict,pty ssh-ed25519 AAAA new\n\
+                 --- web1/authorized_keys\n\
+                 +++ web1/authorized_keys\n\
+                 -ssh-ed25519 AAAA web\n"
+            )
+        );
+        assert!(!plan.is_empty());
+        assert!(AccessPlan::default().is_empty());
+    }
+}
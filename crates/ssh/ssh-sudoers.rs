@@ -0,0 +1,250 @@
+//! `/etc/sudoers.d` fragments rendered from the team graph.
+//!
+//! Each host gets one fragment next to its authorized_keys, so the graph is
+//! the only place that says both who can log in and who can become root.
+//! Rules name unix accounts, so only devices with a login mapping on the host
+//! get one. Devices without a login mapping never get a sudo rule, even SSH
+//! admins.
+//!
+//! Logins are shared accounts: every device that may log in as a login gets
+//! whatever the login may do. A rule is only rendered for a login when every
+//! device that may use it has a reason to become root. Devices without a
+//! mapping may use any login, so one without a reason withholds every rule
+//! on the host.
+
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::{bail, Context, Result};
+use aranya_crypto::UserId;
+use tokio::{fs, io::AsyncWriteExt, process::Command};
+
+use crate::ssh_access::validate_login;
+
+/// First line of every rendered sudoers fragment
+const SUDOERS_HEADER: &str = "# Generated by Aranya SSH Access Manager\n";
+
+/// Distinguishes concurrent checks' temporary files within one process
+static CHECK_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Why a device may become root on a host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SudoReason {
+    /// The device holds `SSH_ADMIN_ROLE`.
+    SshAdmin,
+    /// The device has an admin-level group grant covering the host.
+    GroupGrant,
+}
+
+impl SudoReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::SshAdmin => "ssh-admin",
+            Self::GroupGrant => "group-grant",
+        }
+    }
+}
+
+/// The logins allowed to become root on one host.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Sudoers {
+    rules: BTreeMap<String, Vec<(UserId, SudoReason)>>,
+    /// Devices without a reason to become root, by the login they may use
+    others: BTreeMap<String, Vec<UserId>>,
+    /// Devices without a reason to become root that may use any login
+    unmapped_others: Vec<UserId>,
+}
+
+impl Sudoers {
+    /// Let `login` become root on behalf of `user_id`.
+    ///
+    /// Logins that are not valid unix account names are ignored.
+    pub fn allow(&mut self, login: &str, user_id: UserId, reason: SudoReason) {
+        if validate_login(login).is_err() {
+            return;
+        }
+        self.rules.entry(login.to_string()).or_default().push((user_id, reason));
+    }
+
+    /// Record that `user_id`, which has no reason to become root, may log
+    /// in as `login`, or as any login for `None`.
+    ///
+    /// No rule is rendered for a login such a device may use.
+    pub fn deny(&mut self, login: Option<&str>, user_id: UserId) {
+        match login {
+            Some(login) => self.others.entry(login.to_string()).or_default().push(user_id),
+            None => self.unmapped_others.push(user_id),
+        }
+    }
+
+    /// Reports whether no login may become root.
+    pub fn is_empty(&self) -> bool {
+        self.rules.keys().all(|login| self.withholds(login))
+    }
+
+    /// Logins that would have a rule, but are shared with devices without
+    /// a reason to become root.
+    pub fn withheld(&self) -> impl Iterator<Item = &str> {
+        self.rules.keys().filter(|login| self.withholds(login)).map(String::as_str)
+    }
+
+    /// Reports whether a device without a reason to become root may use
+    /// `login`.
+    fn withholds(&self, login: &str) -> bool {
+        !self.unmapped_others.is_empty() || self.others.contains_key(login)
+    }
+
+    /// The fragment's contents, one rule per login with the devices and
+    /// reasons behind it as comments. Withheld rules are left as comments
+    /// naming the devices withholding them.
+    pub fn render(&self) -> String {
+        let mut out = SUDOERS_HEADER.to_string();
+        for (login, sources) in &self.rules {
+            for (user_id, reason) in sources {
+                out.push_str(&format!("# {} ({})\n", user_id, reason.as_str()));
+            }
+            if !self.withholds(login) {
+                out.push_str(&sudoers_rule(login));
+                continue;
+            }
+            let others = self.others.get(login).into_iter().flatten();
+            for user_id in others.chain(&self.unmapped_others) {
+                out.push_str(&format!("# withheld, {} may also log in as {}\n", user_id, login));
+            }
+        }
+        out
+    }
+}
+
+/// The rule letting `login` run any command as any user.
+///
+/// Logins authenticate with SSH keys and usually have no password, so the
+/// rule does not ask for one.
+pub fn sudoers_rule(login: &str) -> String {
+    format!("{} ALL=(ALL:ALL) NOPASSWD: ALL\n", login)
+}
+
+/// Parse a rendered fragment back into the logins it lets become root.
+///
+/// Anything other than comments and rules in the form `sudoers_rule`
+/// renders is rejected, so a fragment cannot carry `Defaults` or other
+/// directives.
+pub fn sudoers_logins(contents: &str) -> Result<Vec<&str>> {
+    let mut logins = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((login, rule)) = line.split_once(' ') else {
+            bail!("invalid sudoers line `{}`", line);
+        };
+        validate_login(login).with_context(|| format!("invalid sudoers line `{}`", line))?;
+        if sudoers_rule(login).trim_end() != format!("{} {}", login, rule) {
+            bail!("unexpected sudoers rule `{}`", line);
+        }
+        logins.push(login);
+    }
+    Ok(logins)
+}
+
+/// Check a fragment's syntax with `visudo -cf`.
+///
+/// The contents are written to a new temporary file in `dir`, readable only
+/// as sudoers fragments are, which is removed afterwards whether or not the
+/// check passes. An existing file is never overwritten.
+pub async fn check_sudoers(dir: &Path, contents: &[u8]) -> Result<()> {
+    let n = CHECK_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let path = dir.join(format!(".aranya-ssh-sudoers.{}.{}", std::process::id(), n));
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o440)
+        .open(&path)
+        .await
+        .with_context(|| format!("unable to create {}", path.display()))?;
+
+    let result = run_visudo(file, &path, contents).await;
+    let _ = fs::remove_file(&path).await;
+    result
+}
+
+/// Write `contents` to the open temporary file at `path` and check it.
+async fn run_visudo(mut file: fs::File, path: &Path, contents: &[u8]) -> Result<()> {
+    file.write_all(contents)
+        .await
+        .with_context(|| format!("unable to write {}", path.display()))?;
+    file.flush().await?;
+    drop(file);
+
+    let output = Command::new("visudo")
+        .arg("-cf")
+        .arg(path)
+        .output()
+        .await
+        .context("unable to run visudo")?;
+    if !output.status.success() {
+        bail!(
+            "visudo rejected sudoers fragment: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_are_withheld_for_logins_shared_with_other_devices() {
+        let user_id = UserId::default();
+        let mut sudoers = Sudoers::default();
+        sudoers.allow("ops", user_id, SudoReason::SshAdmin);
+        sudoers.allow("deploy", user_id, SudoReason::GroupGrant);
+        sudoers.deny(Some("deploy"), user_id);
+        sudoers.allow("Not A Login", user_id, SudoReason::SshAdmin);
+
+        let rendered = sudoers.render();
+        assert_eq!(sudoers_logins(&rendered).unwrap(), ["ops"]);
+        assert_eq!(sudoers.withheld().collect::<Vec<_>>(), ["deploy"]);
+        assert!(rendered.contains(&format!("# withheld, {} may also log in as deploy\n", user_id)));
+        assert!(!sudoers.is_empty());
+
+        // A device that may use any login withholds every rule
+        sudoers.deny(None, user_id);
+        assert!(sudoers.is_empty());
+        assert!(sudoers_logins(&sudoers.render()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn only_rendered_rules_parse() {
+        let fragment = format!("{}# note\n{}", SUDOERS_HEADER, sudoers_rule("ops"));
+        assert_eq!(sudoers_logins(&fragment).unwrap(), ["ops"]);
+
+        for line in [
+            "Defaults !requiretty",
+            "ops ALL=(ALL:ALL) ALL",
+            "ops ALL=(ALL:ALL) NOPASSWD: ALL, /bin/sh",
+            "Root ALL=(ALL:ALL) NOPASSWD: ALL",
+        ] {
+            assert!(sudoers_logins(line).is_err(), "{}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn checks_leave_no_temporary_file() {
+        let dir = std::env::temp_dir().join(format!("aranya-ssh-sudoers-{}", std::process::id()));
+        fs::create_dir_all(&dir).await.unwrap();
+
+        // Fails without visudo installed, but must clean up either way
+        let _ = check_sudoers(&dir, sudoers_rule("ops").as_bytes()).await;
+        let _ = check_sudoers(&dir, b"not a rule\n").await;
+
+        let mut entries = fs::read_dir(&dir).await.unwrap();
+        assert!(entries.next_entry().await.unwrap().is_none());
+        fs::remove_dir(&dir).await.unwrap();
+    }
+}